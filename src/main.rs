@@ -3,19 +3,28 @@
 //! This application retrieve internals metrics of Sōzu and format them into
 //! prometheus.
 
-use std::{path::PathBuf, sync::Arc};
+use std::{future::Future, path::PathBuf, sync::Arc, time::Duration};
 
 use clap::{ArgAction, Parser};
+use sozu_command_lib::config::Config;
+use tokio::signal::unix::{signal, SignalKind};
 use tracing::{error, info};
 
 use crate::svc::{
-    config::{self, ConnectorConfiguration},
+    config::{self, ConnectorConfiguration, Sozu},
     http,
     logging::{self, LoggingInitGuard},
+    systemd,
 };
 
 pub mod svc;
 
+// -----------------------------------------------------------------------------
+// Constants
+
+/// Time given to the pending events to be forwarded to sentry before exiting
+const SENTRY_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
 // -----------------------------------------------------------------------------
 // Error
 
@@ -30,7 +39,11 @@ pub enum Error {
     #[error("failed to serve http server, {0}")]
     HttpServer(http::server::Error),
     #[error("failed to load sōzu configuration, {0}")]
-    SozuConfiguration(sozu_client::config::Error),
+    SozuConfiguration(Box<sozu_client::config::Error>),
+    #[error("failed to create handler on reload signal, {0}")]
+    Reload(std::io::Error),
+    #[error("failed to build the asynchronous runtime, {0}")]
+    Runtime(std::io::Error),
 }
 
 // -----------------------------------------------------------------------------
@@ -62,20 +75,28 @@ impl paw::ParseArgs for Args {
 // main
 
 #[paw::main]
-#[tokio::main(flavor = "current_thread")]
-async fn main(args: Args) -> Result<(), Error> {
+fn main(args: Args) -> Result<(), Error> {
+    // -------------------------------------------------------------------------
+    // Read what systemd tells the connector before any thread is spawned, as
+    // the variables of socket activation are removed from the environment
+    let systemd = systemd::Environment::take();
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(Error::Runtime)?
+        .block_on(run(args, systemd))
+}
+
+/// Run the connector until it is asked to terminate
+async fn run(args: Args, systemd: systemd::Environment) -> Result<(), Error> {
     // -------------------------------------------------------------------------
     // Retrieve configuration
-    let config = Arc::new(match &args.config {
-        Some(path) => {
-            ConnectorConfiguration::try_from(path.to_owned()).map_err(Error::Configuration)?
-        }
-        None => ConnectorConfiguration::try_new().map_err(Error::Configuration)?,
-    });
+    let config = configuration(&args)?;
 
     // -------------------------------------------------------------------------
     // Initialize logging system
-    let _guard = match &config.sentry {
+    let guard = match &config.sentry {
         Some(sentry_ctx) => {
             logging::initialize_with_sentry(args.verbosity as usize, sentry_ctx.to_owned())
                 .map_err(Error::Logging)?
@@ -86,25 +107,25 @@ async fn main(args: Args) -> Result<(), Error> {
     };
 
     // -------------------------------------------------------------------------
-    // Load Sōzu configuration
-    info!(
-        path = config.sozu.configuration.display().to_string(),
-        "Load Sōzu configuration"
-    );
-    let sozu_config = Arc::new(
-        sozu_client::config::try_from(&config.sozu.configuration)
-            .map_err(Error::SozuConfiguration)?,
-    );
+    // Load Sōzu configurations and create the state of the server
+    let sozu_configs = sozu_configurations(&config)?;
+    let shared = http::server::Shared::new(config, sozu_configs).map_err(Error::HttpServer)?;
 
     // -------------------------------------------------------------------------
-    // Start HTTP server and listener to termination signals concurrently and
-    // not in parallel
-
+    // Start HTTP server, which drains the requests in flight on termination
+    // signals, and listener to reload signals concurrently and not in parallel
+    let shutdown = terminate()?;
     let result = tokio::select! {
-        r = tokio::signal::ctrl_c() => r.map_err(Error::Termination),
-        r = http::server::serve(config, sozu_config) => r.map_err(Error::HttpServer),
+        r = http::server::serve(shared.to_owned(), systemd, shutdown) => r.map_err(Error::HttpServer),
+        r = reload(&args, &shared) => r,
     };
 
+    shared.stop();
+
+    if !guard.flush(SENTRY_FLUSH_TIMEOUT) {
+        error!("Could not forward all pending events to sentry");
+    }
+
     if let Err(err) = result {
         error!(
             error = err.to_string(),
@@ -117,3 +138,78 @@ async fn main(args: Args) -> Result<(), Error> {
     info!("Gracefully halted {}!", env!("CARGO_PKG_NAME"));
     Ok(())
 }
+
+// -----------------------------------------------------------------------------
+// helpers
+
+/// Read the configuration of the connector, from the given file or the default
+/// locations, overridden by the environment
+fn configuration(args: &Args) -> Result<Arc<ConnectorConfiguration>, Error> {
+    Ok(Arc::new(match &args.config {
+        Some(path) => {
+            ConnectorConfiguration::try_from(path.to_owned()).map_err(Error::Configuration)?
+        }
+        None => ConnectorConfiguration::try_new().map_err(Error::Configuration)?,
+    }))
+}
+
+/// Load the configuration of each Sōzu instance to scrape
+fn sozu_configurations(config: &ConnectorConfiguration) -> Result<Vec<(Sozu, Arc<Config>)>, Error> {
+    let mut sozu_configs = Vec::new();
+    for sozu in config.sozu.iter() {
+        info!(
+            instance = sozu.name,
+            path = sozu.configuration.display().to_string(),
+            "Load Sōzu configuration"
+        );
+        let sozu_config = Arc::new(
+            sozu_client::config::try_from(&sozu.configuration)
+                .map_err(|err| Error::SozuConfiguration(Box::new(err)))?,
+        );
+
+        sozu_configs.push((sozu.to_owned(), sozu_config));
+    }
+
+    Ok(sozu_configs)
+}
+
+/// Returns a future completing on the first termination or interrupt signal
+fn terminate() -> Result<impl Future<Output = ()>, Error> {
+    let mut terminate = signal(SignalKind::terminate()).map_err(Error::Termination)?;
+    let mut interrupt = signal(SignalKind::interrupt()).map_err(Error::Termination)?;
+
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => info!("Received termination signal"),
+            _ = interrupt.recv() => info!("Received interrupt signal"),
+        }
+    })
+}
+
+/// Reload the configuration of the connector and of the Sōzu instances on
+/// each hangup signal. A configuration that fails to load is logged and the
+/// current one is kept.
+async fn reload(args: &Args, shared: &http::server::Shared) -> Result<(), Error> {
+    let mut hangup = signal(SignalKind::hangup()).map_err(Error::Reload)?;
+
+    while hangup.recv().await.is_some() {
+        info!("Received hangup signal, reload configuration");
+
+        let result = configuration(args).and_then(|config| {
+            let sozu_configs = sozu_configurations(&config)?;
+            shared
+                .reload(config, sozu_configs)
+                .map_err(Error::HttpServer)
+        });
+
+        match result {
+            Ok(()) => info!("Reloaded configuration"),
+            Err(err) => error!(
+                error = err.to_string(),
+                "Could not reload configuration, keep the current one"
+            ),
+        }
+    }
+
+    Ok(())
+}
@@ -3,15 +3,29 @@
 //! This module provides structures and helpers to interact with the configuration
 
 use std::{
+    collections::{BTreeMap, BTreeSet},
     env::{self, VarError},
+    fmt,
     net::SocketAddr,
     path::PathBuf,
+    time::Duration,
 };
 
-use config::{Config, ConfigError, File};
+use config::{Config, ConfigError, Environment, File, Map, Source};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::svc::logging::SentryContext;
+use crate::svc::{
+    logging::SentryContext,
+    telemetry::{description::Description, prometheus::LabelEncoding},
+};
+
+// -----------------------------------------------------------------------------
+// Constants
+
+/// Prefix of the environment variables overriding the configuration, e.g.
+/// `SOZU_PROMETHEUS_CONNECTOR_LISTENING_ADDRESS`
+pub const ENVIRONMENT_PREFIX: &str = "SOZU_PROMETHEUS_CONNECTOR";
 
 // -----------------------------------------------------------------------------
 // Error
@@ -24,6 +38,12 @@ pub enum Error {
     Serialize(ConfigError),
     #[error("failed to retrieve environment variable '{0}', {1}")]
     EnvironmentVariable(&'static str, VarError),
+    #[error("no Sōzu instance to scrape is configured")]
+    NoSozu,
+    #[error("Sōzu instance '{0}' is configured more than once")]
+    DuplicateSozu(String),
+    #[error("invalid mode '{0}' of unix socket, expected octal permissions e.g. '0660'")]
+    InvalidMode(String),
 }
 
 // -----------------------------------------------------------------------------
@@ -31,8 +51,160 @@ pub enum Error {
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct Sozu {
+    /// Name of the instance, used as the `instance` label and in the
+    /// `/metrics/<name>` route
+    #[serde(rename = "name", default = "Sozu::default_name")]
+    pub name: String,
     #[serde(rename = "configuration")]
     pub configuration: PathBuf,
+    /// Time given to Sōzu to answer a request on its command socket, in
+    /// seconds. Scrapes that announce a shorter timeout are given less.
+    #[serde(rename = "query-timeout", default = "Sozu::default_query_timeout")]
+    pub query_timeout: u64,
+}
+
+impl Sozu {
+    fn default_name() -> String {
+        "default".to_string()
+    }
+
+    fn default_query_timeout() -> u64 {
+        10
+    }
+
+    pub fn query_timeout(&self) -> Duration {
+        // a zero timeout would fail every request
+        Duration::from_secs(self.query_timeout.max(1))
+    }
+}
+
+/// The Sōzu instances to scrape, either one `[sozu]` table as in earlier
+/// releases or a list of named `[[sozu]]` tables
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(untagged)]
+pub enum SozuInstances {
+    One(Sozu),
+    Many(Vec<Sozu>),
+}
+
+impl SozuInstances {
+    pub fn iter(&self) -> impl Iterator<Item = &Sozu> {
+        match self {
+            Self::One(sozu) => std::slice::from_ref(sozu).iter(),
+            Self::Many(instances) => instances.iter(),
+        }
+    }
+
+    /// Whether the instances are configured as a list, in which case the
+    /// metrics exported on `/metrics` are labelled with their `instance`
+    pub fn is_list(&self) -> bool {
+        matches!(self, Self::Many(_))
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Listener
+
+/// A socket on which the routes are served
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(untagged)]
+pub enum Listener {
+    Tcp {
+        #[serde(rename = "address")]
+        address: SocketAddr,
+    },
+    Unix(UnixSocket),
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp { address } => write!(f, "{address}"),
+            Self::Unix(socket) => write!(f, "unix:{}", socket.path.display()),
+        }
+    }
+}
+
+/// A unix socket on which the routes are served, e.g. to expose them to a
+/// local agent only
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct UnixSocket {
+    /// Path of the socket, replaced if it already exists
+    #[serde(rename = "path")]
+    pub path: PathBuf,
+    /// Permissions of the socket, in octal. Defaults to those given by the
+    /// umask of the process.
+    #[serde(rename = "mode")]
+    pub mode: Option<String>,
+    /// Name of the user owning the socket
+    #[serde(rename = "owner")]
+    pub owner: Option<String>,
+    /// Name of the group owning the socket
+    #[serde(rename = "group")]
+    pub group: Option<String>,
+}
+
+impl UnixSocket {
+    pub fn mode(&self) -> Result<Option<u32>, Error> {
+        self.mode
+            .as_ref()
+            .map(|mode| {
+                u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .ok_or_else(|| Error::InvalidMode(mode.to_owned()))
+            })
+            .transpose()
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Tls
+
+/// Serve the routes over TLS on the TCP listeners, optionally requiring a
+/// client certificate
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Tls {
+    /// Path to the certificate chain, in PEM, the certificate of the
+    /// connector first
+    #[serde(rename = "certificate")]
+    pub certificate: PathBuf,
+    /// Path to the private key of the certificate, in PEM
+    #[serde(rename = "key")]
+    pub key: PathBuf,
+    /// Path to the certificates of the authorities, in PEM, that issue the
+    /// certificates clients must present. Clients are not authenticated if
+    /// unset.
+    #[serde(rename = "client-ca")]
+    pub client_ca: Option<PathBuf>,
+}
+
+// -----------------------------------------------------------------------------
+// Collector
+
+/// Poll Sōzu in background and serve scrapes from the last collected metrics
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Collector {
+    /// Interval between two queries to Sōzu, in seconds
+    #[serde(rename = "interval")]
+    pub interval: u64,
+    /// Age of the collected metrics, in seconds, after which they are reported
+    /// as stale. Defaults to three intervals.
+    #[serde(rename = "max-staleness")]
+    pub max_staleness: Option<u64>,
+}
+
+impl Collector {
+    pub fn interval(&self) -> Duration {
+        // a zero interval would make the ticker panic
+        Duration::from_secs(self.interval.max(1))
+    }
+
+    pub fn max_staleness(&self) -> Duration {
+        self.max_staleness
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| 3 * self.interval())
+    }
 }
 
 // -----------------------------------------------------------------------------
@@ -40,15 +212,44 @@ pub struct Sozu {
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct ConnectorConfiguration {
+    /// TCP address on which to listen, optional if `listeners` are given
     #[serde(rename = "listening-address")]
-    pub listening_address: SocketAddr,
+    pub listening_address: Option<SocketAddr>,
+    /// Sockets on which to listen besides `listening-address`
+    #[serde(rename = "listeners", default)]
+    pub listeners: Vec<Listener>,
     /// Emit per-worker metric series (labelled with `worker_id`) in addition to
     /// the aggregated ones. Disabled by default to keep the exported output
     /// byte-identical to earlier releases and to avoid the extra cardinality.
     #[serde(rename = "per-worker-metrics", default)]
     pub per_worker_metrics: bool,
+    /// How label values are written: `escape` preserves them, escaping only
+    /// what the exposition formats require, `url` percent-encodes them as
+    /// earlier releases did. Defaults to `url`, to let dashboards migrate
+    /// deliberately.
+    #[serde(rename = "label-encoding", default)]
+    pub label_encoding: LabelEncoding,
+    /// Time given to the requests in flight to complete once a termination
+    /// signal is received, in seconds
+    #[serde(
+        rename = "drain-timeout",
+        default = "ConnectorConfiguration::default_drain_timeout"
+    )]
+    pub drain_timeout: u64,
+    /// Time during which connections are still accepted once a termination
+    /// signal is received, while `/readyz` fails, in seconds
+    #[serde(rename = "drain-delay", default)]
+    pub drain_delay: u64,
     #[serde(rename = "sozu")]
-    pub sozu: Sozu,
+    pub sozu: SozuInstances,
+    #[serde(rename = "collector")]
+    pub collector: Option<Collector>,
+    /// Help texts and units of metrics, keyed by Sōzu metric name with dots
+    /// replaced by underscores. They take precedence over the built-in catalogue.
+    #[serde(rename = "descriptions", default)]
+    pub descriptions: BTreeMap<String, Description>,
+    #[serde(rename = "tls")]
+    pub tls: Option<Tls>,
     #[serde(rename = "sentry")]
     pub sentry: Option<SentryContext>,
 }
@@ -60,14 +261,28 @@ impl TryFrom<PathBuf> for ConnectorConfiguration {
     fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
         Config::builder()
             .add_source(File::from(path).required(true))
+            .add_source(Overrides(environment()))
             .build()
             .map_err(Error::Build)?
-            .try_deserialize()
-            .map_err(Error::Serialize)
+            .try_deserialize::<Self>()
+            .map_err(Error::Serialize)?
+            .validate()
     }
 }
 
 impl ConnectorConfiguration {
+    fn default_drain_timeout() -> u64 {
+        10
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout)
+    }
+
+    pub fn drain_delay(&self) -> Duration {
+        Duration::from_secs(self.drain_delay)
+    }
+
     #[tracing::instrument]
     pub fn try_new() -> Result<Self, Error> {
         let homedir = env::var("HOME").map_err(|err| Error::EnvironmentVariable("HOME", err))?;
@@ -104,9 +319,593 @@ impl ConnectorConfiguration {
                 .required(false),
             )
             .add_source(File::from(PathBuf::from("config")).required(false))
+            .add_source(Overrides(environment()))
+            .build()
+            .map_err(Error::Build)?
+            .try_deserialize::<Self>()
+            .map_err(Error::Serialize)?
+            .validate()
+    }
+
+    /// Returns the configuration with its secrets redacted, to be displayed
+    pub fn redacted(&self) -> Self {
+        let mut config = self.to_owned();
+        if let Some(sentry) = &mut config.sentry {
+            sentry.dsn = "<redacted>".to_string();
+        }
+
+        config
+    }
+
+    /// Returns the keys whose value changed from this configuration to the
+    /// given one, along with their previous and new values, secrets redacted
+    pub fn diff(&self, other: &Self) -> Vec<String> {
+        let to_value = |config: &Self| {
+            serde_json::to_value(config.redacted()).expect("configuration to be serializable")
+        };
+
+        let mut changes = vec![];
+        compare("", &to_value(self), &to_value(other), &mut changes);
+        changes
+    }
+
+    /// Returns the sockets on which to listen, `listening-address` first
+    pub fn listeners(&self) -> Vec<Listener> {
+        self.listening_address
+            .map(|address| Listener::Tcp { address })
+            .into_iter()
+            .chain(self.listeners.iter().cloned())
+            .collect()
+    }
+
+    /// Check that there is at least one Sōzu instance and that their names
+    /// are unique, as they identify them in the exported metrics, and that
+    /// the unix sockets to listen on are valid
+    fn validate(self) -> Result<Self, Error> {
+        let mut names = Vec::new();
+        for sozu in self.sozu.iter() {
+            if names.contains(&&sozu.name) {
+                return Err(Error::DuplicateSozu(sozu.name.to_owned()));
+            }
+            names.push(&sozu.name);
+        }
+
+        if names.is_empty() {
+            return Err(Error::NoSozu);
+        }
+
+        for listener in self.listeners() {
+            if let Listener::Unix(socket) = listener {
+                socket.mode()?;
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+/// Returns the environment variables overriding the configuration files. They
+/// are named after the keys with the `SOZU_PROMETHEUS_CONNECTOR_` prefix, in
+/// upper snake case, with `__` between a table and its keys, e.g.
+/// `SOZU_PROMETHEUS_CONNECTOR_SOZU__CONFIGURATION` for `configuration` in the
+/// `[sozu]` table. Their keys are turned into paths by [`Overrides`].
+fn environment() -> Environment {
+    Environment::with_prefix(ENVIRONMENT_PREFIX)
+        .prefix_separator("_")
+        .separator("__")
+        .try_parsing(true)
+}
+
+/// The environment variables overriding the configuration files, whose keys
+/// are turned into paths: a number addresses an entry of a list, e.g.
+/// `SOZU_PROMETHEUS_CONNECTOR_SOZU__1__NAME` for `name` in the second
+/// `[[sozu]]` table, and the keys are converted to kebab case, except the
+/// metric names of `[descriptions]`, which are in snake case.
+#[derive(Clone, Debug)]
+struct Overrides(Environment);
+
+impl Source for Overrides {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.to_owned())
+    }
+
+    fn collect(&self) -> Result<Map<String, config::Value>, ConfigError> {
+        Ok(self
+            .0
+            .collect()?
+            .into_iter()
+            .map(|(key, value)| (path(&key), value))
+            .collect())
+    }
+}
+
+/// Returns the path to the key of an environment variable, e.g.
+/// `sozu[1].query-timeout` for `sozu.1.query_timeout`
+fn path(key: &str) -> String {
+    let mut path = String::new();
+    let mut parent = "";
+    for segment in key.split('.') {
+        if path.is_empty() {
+            path.push_str(&segment.replace('_', "-"));
+        } else if segment.parse::<usize>().is_ok() {
+            path.push_str(&format!("[{segment}]"));
+        } else if parent == "descriptions" {
+            path.push_str(&format!(".{segment}"));
+        } else {
+            path.push_str(&format!(".{}", segment.replace('_', "-")));
+        }
+
+        parent = segment;
+    }
+
+    path
+}
+
+/// Push the keys under `path` whose value differs from `before` to `after`,
+/// walking down tables and lists
+fn compare(path: &str, before: &Value, after: &Value, changes: &mut Vec<String>) {
+    let key = |name: &dyn std::fmt::Display| {
+        if path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{path}.{name}")
+        }
+    };
+
+    match (before, after) {
+        (Value::Object(before), Value::Object(after)) => {
+            let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+            for name in names {
+                compare(
+                    &key(name),
+                    before.get(name).unwrap_or(&Value::Null),
+                    after.get(name).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        (Value::Array(before), Value::Array(after)) => {
+            for index in 0..before.len().max(after.len()) {
+                compare(
+                    &key(&index),
+                    before.get(index).unwrap_or(&Value::Null),
+                    after.get(index).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        (before, after) if before != after => {
+            changes.push(format!("{path}: {before} -> {after}"));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use config::FileFormat;
+
+    use super::*;
+
+    fn parse(content: &str) -> Result<ConnectorConfiguration, Error> {
+        Config::builder()
+            .add_source(File::from_str(content, FileFormat::Toml))
             .build()
             .map_err(Error::Build)?
-            .try_deserialize()
-            .map_err(Error::Serialize)
+            .try_deserialize::<ConnectorConfiguration>()
+            .map_err(Error::Serialize)?
+            .validate()
+    }
+
+    #[test]
+    fn parse_sozu_instances() {
+        let config = parse(
+            r#"
+listening-address = "0.0.0.0:3000"
+
+[sozu]
+configuration = "/etc/sozu/config.toml"
+"#,
+        )
+        .expect("one instance to be valid");
+
+        assert!(!config.sozu.is_list());
+        assert_eq!(
+            config
+                .sozu
+                .iter()
+                .map(|s| (s.name.as_str(), s.query_timeout()))
+                .collect::<Vec<_>>(),
+            vec![("default", Duration::from_secs(10))]
+        );
+
+        let config = parse(
+            r#"
+listening-address = "0.0.0.0:3000"
+
+[[sozu]]
+name = "public"
+configuration = "/etc/sozu/public.toml"
+
+[[sozu]]
+name = "internal"
+configuration = "/etc/sozu/internal.toml"
+query-timeout = 2
+"#,
+        )
+        .expect("a list of instances to be valid");
+
+        assert!(config.sozu.is_list());
+        assert_eq!(
+            config
+                .sozu
+                .iter()
+                .map(|s| (s.name.as_str(), s.query_timeout()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("public", Duration::from_secs(10)),
+                ("internal", Duration::from_secs(2))
+            ]
+        );
+
+        assert!(matches!(
+            parse(
+                r#"
+listening-address = "0.0.0.0:3000"
+
+[[sozu]]
+configuration = "/etc/sozu/public.toml"
+
+[[sozu]]
+configuration = "/etc/sozu/internal.toml"
+"#,
+            ),
+            Err(Error::DuplicateSozu(name)) if name == "default"
+        ));
+    }
+
+    #[test]
+    fn override_with_environment() {
+        let content = r#"
+listening-address = "0.0.0.0:3000"
+per-worker-metrics = true
+
+[sozu]
+configuration = "/etc/sozu/config.toml"
+
+[sentry]
+dsn = "https://secret@sentry.example.com/1"
+env = "production"
+"#;
+
+        let variables = [
+            (
+                "SOZU_PROMETHEUS_CONNECTOR_LISTENING_ADDRESS",
+                "127.0.0.1:9000",
+            ),
+            ("SOZU_PROMETHEUS_CONNECTOR_PER_WORKER_METRICS", "false"),
+            ("SOZU_PROMETHEUS_CONNECTOR_LABEL_ENCODING", "escape"),
+            ("SOZU_PROMETHEUS_CONNECTOR_SOZU__QUERY_TIMEOUT", "3"),
+            (
+                "SOZU_PROMETHEUS_CONNECTOR_SENTRY__DSN",
+                "https://other@sentry.example.com/2",
+            ),
+            ("SOZU_PROMETHEUS_CONNECTOR_COLLECTOR__INTERVAL", "15"),
+            ("SOZU_PROMETHEUS_CONNECTOR_COLLECTOR__MAX_STALENESS", "60"),
+            ("OTHER_LISTENING_ADDRESS", "127.0.0.1:9001"),
+        ];
+
+        let config = Config::builder()
+            .add_source(File::from_str(content, FileFormat::Toml))
+            .add_source(Overrides(
+                environment().source(Some(
+                    variables
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect(),
+                )),
+            ))
+            .build()
+            .map_err(Error::Build)
+            .and_then(|config| {
+                config
+                    .try_deserialize::<ConnectorConfiguration>()
+                    .map_err(Error::Serialize)
+            })
+            .expect("configuration to be valid");
+
+        // the environment takes precedence over the file
+        assert_eq!(
+            config.listening_address,
+            Some("127.0.0.1:9000".parse::<SocketAddr>().unwrap())
+        );
+        assert!(!config.per_worker_metrics);
+        assert_eq!(config.label_encoding, LabelEncoding::Escape);
+        assert_eq!(
+            config.collector,
+            Some(Collector {
+                interval: 15,
+                max_staleness: Some(60),
+            })
+        );
+
+        // keys that are not overridden are kept from the file
+        assert_eq!(
+            config.sozu,
+            SozuInstances::One(Sozu {
+                name: "default".to_owned(),
+                configuration: PathBuf::from("/etc/sozu/config.toml"),
+                query_timeout: 3,
+            })
+        );
+        assert_eq!(
+            config.sentry.as_ref().map(|sentry| sentry.dsn.as_str()),
+            Some("https://other@sentry.example.com/2")
+        );
+        assert_eq!(
+            config.sentry.as_ref().map(|sentry| sentry.env.as_str()),
+            Some("production")
+        );
+    }
+
+    /// Parse the configuration from TOML, overridden by the given variables
+    fn overridden(content: &str, variables: &[(&str, &str)]) -> ConnectorConfiguration {
+        Config::builder()
+            .add_source(File::from_str(content, FileFormat::Toml))
+            .add_source(Overrides(
+                environment().source(Some(
+                    variables
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect(),
+                )),
+            ))
+            .build()
+            .and_then(Config::try_deserialize)
+            .expect("configuration to be valid")
+    }
+
+    #[test]
+    fn override_list_entries_with_environment() {
+        let content = r#"
+[[sozu]]
+name = "public"
+configuration = "/etc/sozu/public.toml"
+
+[[sozu]]
+name = "internal"
+configuration = "/etc/sozu/internal.toml"
+"#;
+
+        let config = overridden(
+            content,
+            &[
+                ("SOZU_PROMETHEUS_CONNECTOR_SOZU__0__NAME", "edge"),
+                (
+                    "SOZU_PROMETHEUS_CONNECTOR_SOZU__1__CONFIGURATION",
+                    "/etc/sozu/private.toml",
+                ),
+                ("SOZU_PROMETHEUS_CONNECTOR_SOZU__1__QUERY_TIMEOUT", "3"),
+            ],
+        );
+
+        assert_eq!(
+            config.sozu,
+            SozuInstances::Many(vec![
+                Sozu {
+                    name: "edge".to_owned(),
+                    configuration: PathBuf::from("/etc/sozu/public.toml"),
+                    query_timeout: Sozu::default_query_timeout(),
+                },
+                Sozu {
+                    name: "internal".to_owned(),
+                    configuration: PathBuf::from("/etc/sozu/private.toml"),
+                    query_timeout: 3,
+                },
+            ])
+        );
+
+        // a list may come from the environment alone
+        let config = overridden(
+            "",
+            &[
+                ("SOZU_PROMETHEUS_CONNECTOR_SOZU__0__NAME", "public"),
+                (
+                    "SOZU_PROMETHEUS_CONNECTOR_SOZU__0__CONFIGURATION",
+                    "/etc/sozu/public.toml",
+                ),
+                ("SOZU_PROMETHEUS_CONNECTOR_SOZU__1__NAME", "internal"),
+                (
+                    "SOZU_PROMETHEUS_CONNECTOR_SOZU__1__CONFIGURATION",
+                    "/etc/sozu/internal.toml",
+                ),
+            ],
+        );
+        assert_eq!(
+            config
+                .sozu
+                .iter()
+                .map(|sozu| &*sozu.name)
+                .collect::<Vec<_>>(),
+            ["public", "internal"]
+        );
+    }
+
+    #[test]
+    fn override_descriptions_with_environment() {
+        let content = r#"
+[sozu]
+configuration = "/etc/sozu/config.toml"
+
+[descriptions.bytes_in]
+help = "Bytes received"
+"#;
+
+        let config = overridden(
+            content,
+            &[
+                (
+                    "SOZU_PROMETHEUS_CONNECTOR_DESCRIPTIONS__BYTES_IN__HELP",
+                    "Bytes received from clients",
+                ),
+                (
+                    "SOZU_PROMETHEUS_CONNECTOR_DESCRIPTIONS__MY_LATENCY__HELP",
+                    "Latency of my thing",
+                ),
+                (
+                    "SOZU_PROMETHEUS_CONNECTOR_DESCRIPTIONS__MY_LATENCY__UNIT",
+                    "seconds",
+                ),
+            ],
+        );
+
+        // the metric names keep their snake case
+        assert_eq!(
+            config.descriptions,
+            BTreeMap::from([
+                (
+                    "bytes_in".to_owned(),
+                    Description::new("Bytes received from clients", None),
+                ),
+                (
+                    "my_latency".to_owned(),
+                    Description::new("Latency of my thing", Some("seconds")),
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn redact_configuration() {
+        let config = parse(
+            r#"
+listening-address = "0.0.0.0:3000"
+
+[sozu]
+configuration = "/etc/sozu/config.toml"
+
+[sentry]
+dsn = "https://secret@sentry.example.com/1"
+env = "production"
+"#,
+        )
+        .expect("configuration to be valid");
+
+        let redacted = config.redacted();
+        assert_eq!(
+            redacted.sentry.as_ref().map(|sentry| sentry.dsn.as_str()),
+            Some("<redacted>")
+        );
+        assert_eq!(redacted.sozu, config.sozu);
+    }
+
+    #[test]
+    fn diff_configurations() {
+        let before = parse(
+            r#"
+listening-address = "0.0.0.0:3000"
+
+[[sozu]]
+name = "public"
+configuration = "/etc/sozu/public.toml"
+
+[sentry]
+dsn = "https://secret@sentry.example.com/1"
+env = "production"
+"#,
+        )
+        .expect("configuration to be valid");
+
+        let after = parse(
+            r#"
+listening-address = "0.0.0.0:3000"
+per-worker-metrics = true
+
+[[sozu]]
+name = "public"
+configuration = "/etc/sozu/public.toml"
+query-timeout = 5
+
+[[sozu]]
+name = "private"
+configuration = "/etc/sozu/private.toml"
+
+[sentry]
+dsn = "https://other@sentry.example.com/1"
+env = "production"
+"#,
+        )
+        .expect("configuration to be valid");
+
+        assert!(before.diff(&before).is_empty());
+        assert_eq!(
+            before.diff(&after),
+            vec![
+                "per-worker-metrics: false -> true".to_owned(),
+                "sozu.0.query-timeout: 10 -> 5".to_owned(),
+                r#"sozu.1: null -> {"configuration":"/etc/sozu/private.toml","name":"private","query-timeout":10}"#.to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_listeners() {
+        let config = parse(
+            r#"
+[[listeners]]
+address = "127.0.0.1:3000"
+
+[[listeners]]
+path = "/run/sozu-prometheus-connector/metrics.sock"
+mode = "0660"
+group = "prometheus"
+
+[sozu]
+configuration = "/etc/sozu/config.toml"
+"#,
+        )
+        .expect("configuration to be valid");
+
+        let listeners = config.listeners();
+        assert_eq!(
+            listeners
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "127.0.0.1:3000".to_owned(),
+                "unix:/run/sozu-prometheus-connector/metrics.sock".to_owned(),
+            ]
+        );
+        match &listeners[1] {
+            Listener::Unix(socket) => {
+                assert_eq!(socket.mode().expect("mode to be valid"), Some(0o660));
+                assert_eq!(socket.owner, None);
+                assert_eq!(socket.group.as_deref(), Some("prometheus"));
+            }
+            listener => panic!("expected a unix socket, got {listener}"),
+        }
+
+        assert!(matches!(
+            parse(
+                r#"
+[[listeners]]
+path = "/run/sozu-prometheus-connector/metrics.sock"
+mode = "rw-rw----"
+
+[sozu]
+configuration = "/etc/sozu/config.toml"
+"#,
+            ),
+            Err(Error::InvalidMode(_))
+        ));
+        // the sockets may be passed by systemd instead
+        assert!(parse(
+            r#"
+[sozu]
+configuration = "/etc/sozu/config.toml"
+"#,
+        )
+        .is_ok_and(|config| config.listeners().is_empty()));
     }
 }
@@ -2,27 +2,53 @@
 //!
 //! This module provides handlers to use with the server implementation
 
-use std::time::SystemTime;
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
 
 use axum::{
     body::Body,
-    extract::State,
-    http::{header, HeaderValue, Request, Response, StatusCode},
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, Request, Response, StatusCode},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse,
+    },
 };
+use futures_util::{future, StreamExt};
 use prometheus::{Encoder, TextEncoder};
-use sozu_client::Sender;
-use sozu_command_lib::proto::command::{
-    self, request::RequestType, response_content::ContentType, QueryMetricsOptions, ResponseContent,
-};
+use sozu_command_lib::proto::command::{EventKind, QueryMetricsOptions, RunState};
 use tracing::{debug, error};
 
-use crate::svc::{http::server, telemetry::prometheus::convert_metrics_to_prometheus};
+use crate::svc::{
+    http::server,
+    sozu,
+    telemetry::{
+        collector::{self, Category},
+        events,
+        openmetrics::{self, OpenMetricsEncoder},
+        prometheus::Format,
+    },
+};
 
 // -----------------------------------------------------------------------------
 // Constants
 
 pub const X_REQUEST_ID: &str = "X-Request-Id";
 pub const X_TIMESTAMP: &str = "X-Timestamp";
+pub const X_PROMETHEUS_SCRAPE_TIMEOUT_SECONDS: &str = "X-Prometheus-Scrape-Timeout-Seconds";
+
+/// Time kept from the timeout announced by Prometheus to answer the scrape
+pub const SCRAPE_TIMEOUT_OFFSET: Duration = Duration::from_millis(500);
+
+/// Time given to Sōzu to answer a readiness probe, or its query timeout if
+/// shorter, so that the probe answers before its own timeout
+pub const READINESS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time given to Sōzu to answer the status request of `/status`, or its query
+/// timeout if shorter, so that unreachable instances do not hold the document
+pub const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
 
 // -----------------------------------------------------------------------------
 // Not found
@@ -75,131 +101,387 @@ pub async fn healthz(req: Request<Body>) -> Response<Body> {
 }
 
 // -----------------------------------------------------------------------------
-// Telemetry
+// Readyz
+
+/// Ready when every Sōzu instance answers a `ListWorkers` request in time, and
+/// the connector is not shutting down. The instances are probed concurrently.
+#[tracing::instrument(skip(shared))]
+pub async fn readyz(State(shared): State<server::Shared>, req: Request<Body>) -> Response<Body> {
+    if shared.is_draining() {
+        return json_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "connector is shutting down",
+        );
+    }
 
-#[tracing::instrument]
-/// Retrieve Sōzu internals and connector telemetry
-pub async fn telemetry(State(state): State<server::State>, _req: Request<Body>) -> Response<Body> {
-    let mut buf = vec![];
-    let mut res = Response::default();
+    let state = shared.load();
+    let probes = future::join_all(state.instances.iter().map(|instance| async move {
+        let deadline = instance.deadline(Some(READINESS_TIMEOUT));
+        (
+            instance,
+            sozu::list_workers(&instance.connection, deadline).await,
+        )
+    }))
+    .await;
+
+    let mut failures = serde_json::Map::new();
+    for (instance, result) in probes {
+        if let Err(err) = result {
+            error!(
+                instance = instance.name,
+                error = err.to_string(),
+                "Sōzu is not ready"
+            );
+            failures.insert(instance.name.to_owned(), err.to_string().into());
+        }
+    }
 
-    // -------------------------------------------------------------------------
-    // Query Sōzu to get its internal metrics
-    debug!("Querying Sōzu metrics");
-    let per_worker_metrics = state.config.per_worker_metrics;
-    let sozu_metrics = match state
-        .client
-        .send(RequestType::QueryMetrics(QueryMetricsOptions {
-            workers: per_worker_metrics,
-            ..Default::default()
-        }))
-        .await
-    {
-        Ok(command::Response {
-            content:
-                Some(ResponseContent {
-                    content_type: Some(ContentType::Metrics(aggregated_metrics)),
-                }),
-            ..
-        }) => convert_metrics_to_prometheus(aggregated_metrics, per_worker_metrics),
-        Ok(response) => {
-            let headers = res.headers_mut();
-            let message = serde_json::json!({
-                "error":
-                    format!(
-                        "Could not query Sōzu on its command socket, got response status {}",
-                        response.status
-                    )
-            })
-            .to_string();
+    if failures.is_empty() {
+        return healthz(req).await;
+    }
 
-            headers.insert(
-                header::CONTENT_TYPE,
-                HeaderValue::from_str(mime::APPLICATION_JSON.as_ref())
-                    .expect("constant to be iso8859-1 compliant"),
-            );
+    json(
+        StatusCode::SERVICE_UNAVAILABLE,
+        serde_json::json!({
+            "error": "Sōzu could not be reached on its command socket",
+            "instances": failures,
+        }),
+    )
+}
 
-            headers.insert(
-                header::CONTENT_LENGTH,
-                HeaderValue::from_str(&message.len().to_string())
-                    .expect("buffer size to be iso8859-1 compliant"),
-            );
+// -----------------------------------------------------------------------------
+// Status
+
+/// Describe the connector, its configuration and the Sōzu instances it scrapes.
+/// The instances are asked for their workers concurrently.
+#[tracing::instrument(skip(shared))]
+pub async fn status(State(shared): State<server::Shared>) -> Response<Body> {
+    let state = shared.load();
+    let probes = future::join_all(state.instances.iter().map(|instance| async move {
+        let deadline = instance.deadline(Some(STATUS_TIMEOUT));
+        (instance, sozu::status(&instance.connection, deadline).await)
+    }))
+    .await;
+
+    let mut instances = Vec::with_capacity(probes.len());
+    for (instance, result) in probes {
+        let workers = match result {
+            Ok(workers) => serde_json::json!(workers
+                .iter()
+                .map(|worker| {
+                    serde_json::json!({
+                        "id": worker.id,
+                        "pid": worker.pid,
+                        "run_state": RunState::try_from(worker.run_state)
+                            .map(|run_state| run_state.as_str_name())
+                            .unwrap_or("UNKNOWN"),
+                    })
+                })
+                .collect::<Vec<_>>()),
+            Err(err) => {
+                error!(
+                    instance = instance.name,
+                    error = err.to_string(),
+                    "Could not retrieve the status of Sōzu"
+                );
+                serde_json::json!({ "error": err.to_string() })
+            }
+        };
+
+        let report = instance
+            .report
+            .read()
+            .expect("report lock to not be poisoned")
+            .to_owned();
+
+        instances.push(serde_json::json!({
+            "name": instance.name,
+            "configuration": instance.configuration,
+            "command-socket": instance.properties.socket,
+            "connected": instance.connection.is_connected(),
+            "workers": workers,
+            "last-scrape": report.retrieved_at.map(|retrieved_at| serde_json::json!({
+                "up": report.up,
+                "duration": report.duration.as_secs_f64(),
+                "series": report.series,
+                "error": report.error,
+                "retrieved-at": timestamp(retrieved_at),
+                "last-success": report.last_success.map(timestamp),
+            })),
+        }));
+    }
 
-            *res.status_mut() = StatusCode::OK;
-            *res.body_mut() = Body::from(message);
+    json(
+        StatusCode::OK,
+        serde_json::json!({
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime": state.started_at.elapsed().unwrap_or_default().as_secs(),
+            "configuration": state.config.redacted(),
+            "instances": instances,
+        }),
+    )
+}
+
+/// Returns the number of seconds elapsed since the unix epoch
+fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
+// -----------------------------------------------------------------------------
+// Events
+
+/// Stream the events pushed by the Sōzu instances as server-sent events, one
+/// JSON document per event. The events may be restricted to some clusters with
+/// `cluster_id` query parameters, which may be repeated.
+#[tracing::instrument(skip_all)]
+pub async fn events(State(shared): State<server::Shared>, req: Request<Body>) -> Response<Body> {
+    let state = shared.load();
+    let cluster_ids = match event_filters(req.uri().query()) {
+        Ok(cluster_ids) => cluster_ids,
+        Err(err) => {
             error!(
-                status = response.status,
-                "Could not query Sōzu on its command socket, got an invalid response"
+                error = err,
+                "Could not parse the query parameters of the events"
             );
-            return res;
+            return json_error(StatusCode::BAD_REQUEST, &err);
         }
-        Err(err) => {
-            let headers = res.headers_mut();
-            let message = serde_json::json!({"error": err.to_string() }).to_string();
+    };
 
-            headers.insert(
-                header::CONTENT_TYPE,
-                HeaderValue::from_str(mime::APPLICATION_JSON.as_ref())
-                    .expect("constant to be iso8859-1 compliant"),
-            );
+    // the streams end once the server drains, as they would never complete
+    let stream = events::stream(&state.instances, cluster_ids)
+        .take_until(shared.drained())
+        .map(|(instance, event)| {
+            let kind = EventKind::try_from(event.kind)
+                .map(|kind| kind.as_str_name())
+                .unwrap_or("UNKNOWN");
+
+            sse::Event::default()
+                .event(kind)
+                .json_data(serde_json::json!({
+                    "instance": instance,
+                    "kind": kind,
+                    "cluster-id": event.cluster_id,
+                    "backend-id": event.backend_id,
+                    "address": event.address.map(|address| SocketAddr::from(address).to_string()),
+                }))
+        });
+
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
 
-            headers.insert(
-                header::CONTENT_LENGTH,
-                HeaderValue::from_str(&message.len().to_string())
-                    .expect("buffer size to be iso8859-1 compliant"),
+// -----------------------------------------------------------------------------
+// Telemetry
+
+/// Build the options of the `QueryMetrics` request from the query string of
+/// the scrape, e.g. `cluster_id=a&cluster_id=b&backend_id=x&metric=requests&no_clusters=true`.
+///
+/// `cluster_id`, `backend_id` and `metric` may be repeated, unknown parameters
+/// are ignored. Keys and values are percent-decoded.
+pub fn query_metrics_options(
+    query: Option<&str>,
+    workers: bool,
+) -> Result<QueryMetricsOptions, String> {
+    let mut opts = QueryMetricsOptions {
+        workers,
+        ..Default::default()
+    };
+
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        let value = value.into_owned();
+        match &*key {
+            "cluster_id" => opts.cluster_ids.push(value),
+            "backend_id" => opts.backend_ids.push(value),
+            "metric" => opts.metric_names.push(value),
+            "no_clusters" => {
+                // a bare `no_clusters` flag is understood as `no_clusters=true`
+                opts.no_clusters = value.is_empty()
+                    || value.parse().map_err(|_| {
+                        format!("failed to parse query parameter 'no_clusters', got '{value}'")
+                    })?;
+            }
+            _ => debug!(key = &*key, "Ignoring unknown query parameter"),
+        }
+    }
+
+    Ok(opts)
+}
+
+/// Returns the clusters to which the events are restricted, from the query
+/// string of the request, e.g. `cluster_id=a&cluster_id=b`. Sōzu pushes events
+/// that no other parameter could filter, so any other one is refused.
+pub fn event_filters(query: Option<&str>) -> Result<Vec<String>, String> {
+    form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .map(|(key, value)| match &*key {
+            "cluster_id" => Ok(value.into_owned()),
+            _ => Err(format!("unsupported query parameter '{key}'")),
+        })
+        .collect()
+}
+
+/// Returns the time given to Sōzu to answer a scrape, from the timeout that
+/// Prometheus announces in the `X-Prometheus-Scrape-Timeout-Seconds` header,
+/// minus the time kept to answer the scrape
+pub fn scrape_timeout(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers
+        .get(X_PROMETHEUS_SCRAPE_TIMEOUT_SECONDS)?
+        .to_str()
+        .ok()?
+        .parse::<f64>()
+        .ok()?;
+
+    let timeout = Duration::try_from_secs_f64(seconds).ok()?;
+
+    // a short timeout is split in halves rather than consumed by the offset
+    Some(
+        timeout
+            .saturating_sub(SCRAPE_TIMEOUT_OFFSET)
+            .max(timeout / 2),
+    )
+}
+
+/// Select the status of a failed scrape from the kind of failure
+fn scrape_status(category: Category) -> StatusCode {
+    match category {
+        Category::Unreachable | Category::Stale => StatusCode::SERVICE_UNAVAILABLE,
+        Category::InvalidResponse => StatusCode::BAD_GATEWAY,
+        Category::Timeout => StatusCode::GATEWAY_TIMEOUT,
+    }
+}
+
+/// Answer with a JSON error message
+fn json_error(status: StatusCode, message: &str) -> Response<Body> {
+    json(status, serde_json::json!({ "error": message }))
+}
+
+/// Answer with a JSON body
+fn json(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+    let mut res = Response::default();
+    let headers = res.headers_mut();
+    let message = value.to_string();
+
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(mime::APPLICATION_JSON.as_ref())
+            .expect("constant to be iso8859-1 compliant"),
+    );
+
+    headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from_str(&message.len().to_string())
+            .expect("buffer size to be iso8859-1 compliant"),
+    );
+
+    *res.status_mut() = status;
+    *res.body_mut() = Body::from(message);
+    res
+}
+
+#[tracing::instrument]
+/// Retrieve the internals of all Sōzu instances and connector telemetry
+pub async fn telemetry(State(shared): State<server::Shared>, req: Request<Body>) -> Response<Body> {
+    let state = shared.load();
+    scrape(state, None, req).await
+}
+
+#[tracing::instrument]
+/// Retrieve the internals of one Sōzu instance and connector telemetry
+pub async fn instance_telemetry(
+    State(shared): State<server::Shared>,
+    Path(instance): Path<String>,
+    req: Request<Body>,
+) -> Response<Body> {
+    let state = shared.load();
+    if state.instances(Some(&instance)).next().is_none() {
+        return json_error(
+            StatusCode::NOT_FOUND,
+            &format!("Sōzu instance '{instance}' is not configured"),
+        );
+    }
+
+    scrape(state, Some(&instance), req).await
+}
+
+/// Answer a scrape of the instance named `scope`, or of all instances
+async fn scrape(state: server::State, scope: Option<&str>, req: Request<Body>) -> Response<Body> {
+    let mut buf = vec![];
+    let mut res = Response::default();
+
+    // -------------------------------------------------------------------------
+    // Negotiate the exposition format and parse the filters given by the scraper
+    let format = Format::negotiate(
+        req.headers()
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok()),
+    );
+
+    let opts = match query_metrics_options(req.uri().query(), state.config.per_worker_metrics) {
+        Ok(opts) => opts,
+        Err(err) => {
+            error!(
+                error = err,
+                "Could not parse the query parameters of the scrape"
             );
+            return json_error(StatusCode::BAD_REQUEST, &err);
+        }
+    };
 
-            *res.status_mut() = StatusCode::OK;
-            *res.body_mut() = Body::from(message);
+    // -------------------------------------------------------------------------
+    // Retrieve Sōzu internal metrics, from the snapshots collected in background
+    // if possible, or by querying Sōzu otherwise. On failure, the instances are
+    // reported as down in a parseable body.
+    let exposition = match &state.config.collector {
+        Some(collector) if !collector::filtered(&opts) => {
+            collector::from_snapshots(&state, scope, format, collector.max_staleness())
+        }
+        _ => {
+            let timeout = scrape_timeout(req.headers());
+            collector::fetch(&state, scope, opts, format, timeout).await
+        }
+    };
 
+    let status = match &exposition.error {
+        None => StatusCode::OK,
+        Some(err) => {
             error!(
                 error = err.to_string(),
-                "Could not query Sōzu on its command socket"
+                category = err.category().to_string(),
+                "Could not retrieve Sōzu metrics"
             );
-            return res;
+            scrape_status(err.category())
         }
     };
 
     // -------------------------------------------------------------------------
     // Retrieve internals telemetry
 
-    let encoder = TextEncoder::new();
     let metrics = prometheus::gather();
+    let result = match format {
+        Format::Text => TextEncoder::new().encode(&metrics, &mut buf),
+        Format::OpenMetrics => OpenMetricsEncoder::new().encode(&metrics, &mut buf),
+    };
 
-    if let Err(err) = encoder.encode(&metrics, &mut buf) {
-        let headers = res.headers_mut();
-        let message = serde_json::json!({"error": err.to_string() }).to_string();
-
-        headers.insert(
-            header::CONTENT_TYPE,
-            HeaderValue::from_str(mime::APPLICATION_JSON.as_ref())
-                .expect("constant to be iso8859-1 compliant"),
-        );
-
-        headers.insert(
-            header::CONTENT_LENGTH,
-            HeaderValue::from_str(&message.len().to_string())
-                .expect("buffer size to be iso8859-1 compliant"),
-        );
-
-        *res.status_mut() = StatusCode::OK;
-        *res.body_mut() = Body::from(message);
-
-        return res;
+    if let Err(err) = result {
+        return json_error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string());
     }
 
     // -------------------------------------------------------------------------
     // Answer to http request
 
-    buf.append(&mut sozu_metrics.into_bytes());
+    buf.extend_from_slice(exposition.body.as_bytes());
+    if format == Format::OpenMetrics {
+        buf.extend_from_slice(openmetrics::EOF.as_bytes());
+    }
 
     let headers = res.headers_mut();
 
     headers.insert(
         header::CONTENT_TYPE,
-        HeaderValue::from_str(mime::TEXT_PLAIN_UTF_8.as_ref())
-            .expect("constant to be iso8859-1 compliant"),
+        HeaderValue::from_str(format.content_type()).expect("constant to be iso8859-1 compliant"),
     );
 
     headers.insert(
@@ -208,8 +490,109 @@ pub async fn telemetry(State(state): State<server::State>, _req: Request<Body>)
             .expect("buffer size to be iso8859-1 compliant"),
     );
 
-    *res.status_mut() = StatusCode::OK;
+    *res.status_mut() = status;
     *res.body_mut() = Body::from(buf);
 
     res
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_query_metrics_options() {
+        assert_eq!(
+            query_metrics_options(None, true),
+            Ok(QueryMetricsOptions {
+                workers: true,
+                ..Default::default()
+            })
+        );
+
+        let opts = query_metrics_options(
+            Some("cluster_id=a&cluster_id=http%3A%2F%2Fb&backend_id=x&metric=requests&no_clusters=true&foo=bar"),
+            false,
+        )
+        .expect("query string to be valid");
+
+        assert_eq!(
+            opts,
+            QueryMetricsOptions {
+                cluster_ids: vec!["a".to_owned(), "http://b".to_owned()],
+                backend_ids: vec!["x".to_owned()],
+                metric_names: vec!["requests".to_owned()],
+                no_clusters: true,
+                ..Default::default()
+            }
+        );
+
+        assert!(
+            query_metrics_options(Some("no_clusters"), false).is_ok_and(|opts| opts.no_clusters)
+        );
+        assert!(query_metrics_options(Some("no_clusters=maybe"), false).is_err());
+
+        // keys are decoded as well as values
+        assert_eq!(
+            query_metrics_options(Some("cluster%5Fid=a+b&metric=%72equests"), false)
+                .map(|opts| (opts.cluster_ids, opts.metric_names)),
+            Ok((vec!["a b".to_owned()], vec!["requests".to_owned()]))
+        );
+    }
+
+    #[test]
+    fn parse_event_filters() {
+        assert_eq!(event_filters(None), Ok(vec![]));
+        assert_eq!(
+            event_filters(Some("cluster_id=a&cluster%5Fid=http%3A%2F%2Fb")),
+            Ok(vec!["a".to_owned(), "http://b".to_owned()])
+        );
+        assert!(event_filters(Some("cluster_id=a&backend_id=x")).is_err());
+        assert!(event_filters(Some("metric=requests")).is_err());
+        assert!(event_filters(Some("no_clusters=true")).is_err());
+    }
+
+    #[test]
+    fn parse_scrape_timeout() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                X_PROMETHEUS_SCRAPE_TIMEOUT_SECONDS,
+                HeaderValue::from_str(value).expect("value to be iso8859-1 compliant"),
+            );
+            headers
+        };
+
+        assert_eq!(scrape_timeout(&HeaderMap::new()), None);
+        assert_eq!(
+            scrape_timeout(&headers("10")),
+            Some(Duration::from_millis(9_500))
+        );
+        assert_eq!(
+            scrape_timeout(&headers("0.6")),
+            Some(Duration::from_millis(300))
+        );
+        assert_eq!(scrape_timeout(&headers("-1")), None);
+        assert_eq!(scrape_timeout(&headers("soon")), None);
+    }
+
+    #[test]
+    fn select_scrape_status() {
+        assert_eq!(
+            scrape_status(Category::Stale),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            scrape_status(Category::Unreachable),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            scrape_status(Category::Timeout),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(
+            scrape_status(Category::InvalidResponse),
+            StatusCode::BAD_GATEWAY
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! # Listener module
+//!
+//! This module binds the sockets on which the routes are served, either TCP
+//! addresses or unix sockets with their permissions and owner.
+
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io::ErrorKind,
+    os::unix::fs::{chown, DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+use nix::unistd::{Group, User};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::debug;
+
+use crate::svc::config::{self, Listener, UnixSocket};
+
+// -----------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to bind on socket '{0}', {1}")]
+    Bind(Listener, std::io::Error),
+    #[error("failed to remove stale unix socket '{0}', {1}")]
+    RemoveSocket(PathBuf, std::io::Error),
+    #[error("'{0}' already exists and is not a unix socket")]
+    NotASocket(PathBuf),
+    #[error("failed to set permissions of unix socket '{0}', {1}")]
+    Permissions(PathBuf, std::io::Error),
+    #[error("failed to set owner of unix socket '{0}', {1}")]
+    Owner(PathBuf, std::io::Error),
+    #[error("failed to create private directory '{0}' to bind unix socket in, {1}")]
+    Staging(PathBuf, std::io::Error),
+    #[error("failed to move unix socket to '{0}', {1}")]
+    Rename(PathBuf, std::io::Error),
+    #[error("failed to look up user or group '{0}', {1}")]
+    LookUp(String, nix::Error),
+    #[error("user '{0}' does not exist")]
+    UnknownUser(String),
+    #[error("group '{0}' does not exist")]
+    UnknownGroup(String),
+    #[error("invalid unix socket, {0}")]
+    Mode(config::Error),
+}
+
+// -----------------------------------------------------------------------------
+// Bound
+
+/// A socket bound and ready to accept connections
+#[derive(Debug)]
+pub enum Bound {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+// -----------------------------------------------------------------------------
+// helpers
+
+/// Bind the socket to listen on
+#[tracing::instrument]
+pub async fn bind(listener: &Listener) -> Result<Bound, Error> {
+    match listener {
+        Listener::Tcp { address } => TcpListener::bind(address)
+            .await
+            .map(Bound::Tcp)
+            .map_err(|err| Error::Bind(listener.to_owned(), err)),
+        Listener::Unix(socket) => bind_unix(socket).map(Bound::Unix),
+    }
+}
+
+/// Bind the unix socket, replacing the one left by a previous run if any, and
+/// set its permissions and owner.
+///
+/// The socket is bound in a directory next to it that only the connector can
+/// enter, and moved into place once its permissions and owner are set, so that
+/// it is never reachable with the looser permissions given by the umask.
+fn bind_unix(socket: &UnixSocket) -> Result<UnixListener, Error> {
+    let mode = socket.mode().map_err(Error::Mode)?;
+    let uid = socket.owner.as_deref().map(uid).transpose()?;
+    let gid = socket.group.as_deref().map(gid).transpose()?;
+
+    match fs::symlink_metadata(&socket.path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            debug!(
+                path = socket.path.display().to_string(),
+                "Remove stale unix socket"
+            );
+            fs::remove_file(&socket.path)
+                .map_err(|err| Error::RemoveSocket(socket.path.to_owned(), err))?;
+        }
+        Ok(_) => return Err(Error::NotASocket(socket.path.to_owned())),
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(Error::RemoveSocket(socket.path.to_owned(), err)),
+    }
+
+    let staging = staging(&socket.path);
+    if staging.exists() {
+        fs::remove_dir_all(&staging).map_err(|err| Error::Staging(staging.to_owned(), err))?;
+    }
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .map_err(|err| Error::Staging(staging.to_owned(), err))?;
+
+    let staged = staging.join("socket");
+    let result = (|| {
+        let listener = UnixListener::bind(&staged)
+            .map_err(|err| Error::Bind(Listener::Unix(socket.to_owned()), err))?;
+
+        if let Some(mode) = mode {
+            fs::set_permissions(&staged, Permissions::from_mode(mode))
+                .map_err(|err| Error::Permissions(socket.path.to_owned(), err))?;
+        }
+
+        if uid.is_some() || gid.is_some() {
+            chown(&staged, uid, gid).map_err(|err| Error::Owner(socket.path.to_owned(), err))?;
+        }
+
+        fs::rename(&staged, &socket.path)
+            .map_err(|err| Error::Rename(socket.path.to_owned(), err))?;
+
+        Ok(listener)
+    })();
+
+    if let Err(err) = fs::remove_dir_all(&staging) {
+        debug!(
+            path = staging.display().to_string(),
+            error = err.to_string(),
+            "Could not remove the directory the unix socket was bound in"
+        );
+    }
+
+    result
+}
+
+/// Returns the private directory in which the unix socket at `path` is bound,
+/// next to it so that the socket can be renamed into place
+fn staging(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.{}", process::id()))
+}
+
+/// Remove the unix socket once it is no longer listened on
+pub fn unbind(listener: &Listener) {
+    if let Listener::Unix(socket) = listener {
+        if let Err(err) = fs::remove_file(&socket.path) {
+            debug!(
+                path = socket.path.display().to_string(),
+                error = err.to_string(),
+                "Could not remove unix socket"
+            );
+        }
+    }
+}
+
+fn uid(name: &str) -> Result<u32, Error> {
+    User::from_name(name)
+        .map_err(|err| Error::LookUp(name.to_owned(), err))?
+        .map(|user| user.uid.as_raw())
+        .ok_or_else(|| Error::UnknownUser(name.to_owned()))
+}
+
+fn gid(name: &str) -> Result<u32, Error> {
+    Group::from_name(name)
+        .map_err(|err| Error::LookUp(name.to_owned(), err))?
+        .map(|group| group.gid.as_raw())
+        .ok_or_else(|| Error::UnknownGroup(name.to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn bind_unix_socket() {
+        let path = std::env::temp_dir().join(format!(
+            "sozu-prometheus-connector-{}.sock",
+            std::process::id()
+        ));
+        let listener = Listener::Unix(UnixSocket {
+            path: path.to_owned(),
+            mode: Some("0600".to_owned()),
+            owner: None,
+            group: None,
+        });
+
+        // the socket left by a previous run is replaced
+        for _ in 0..2 {
+            let bound = bind(&listener).await.expect("socket to be bound");
+            assert!(matches!(bound, Bound::Unix(_)));
+        }
+
+        let metadata = fs::metadata(&path).expect("socket to exist");
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+        assert!(!staging(&path).exists());
+
+        fs::remove_file(&path).expect("socket to be removed");
+        fs::write(&path, "").expect("file to be written");
+        assert!(matches!(bind(&listener).await, Err(Error::NotASocket(_))));
+        fs::remove_file(&path).expect("file to be removed");
+    }
+}
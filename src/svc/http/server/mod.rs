@@ -3,39 +3,188 @@
 //! This module provides a server implementation with a router based on the
 //! crate [`axum`].
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    future::Future,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
 
 use axum::{
     middleware,
     routing::{any, get},
     Router,
 };
-use sozu_client::{channel::ConnectionProperties, config::canonicalize_command_socket, Client};
-use sozu_command_lib::config::Config;
-use tokio::net::TcpListener;
-use tracing::{debug, info};
+use futures_util::future;
+use sozu_client::{channel::ConnectionProperties, config::canonicalize_command_socket};
+use sozu_command_lib::{config::Config, proto::command::Event};
+use tokio::{
+    sync::{broadcast, watch},
+    task::AbortHandle,
+    time,
+};
+use tracing::{debug, info, warn};
 
-use crate::svc::config::ConnectorConfiguration;
+use self::{listener::Bound, tls::Tls};
+use crate::svc::{
+    config::{ConnectorConfiguration, Sozu},
+    sozu::{self, Deadline},
+    systemd,
+    telemetry::{
+        collector::{self, Flights, Report, Snapshot},
+        events,
+        inventory::{self, Clusters, Expirations, Inventory},
+    },
+};
 
 // -----------------------------------------------------------------------------
 // Export module
 
 pub mod handler;
 pub mod layer;
+pub mod listener;
+pub mod tls;
 
 // -----------------------------------------------------------------------------
 // Error
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("failed to bind on socket '{0}', {1}")]
-    Bind(SocketAddr, std::io::Error),
+    #[error("failed to bind listener, {0}")]
+    Bind(listener::Error),
     #[error("failed to listen on socket '{0}', {1}")]
-    Serve(SocketAddr, std::io::Error),
-    #[error("failed to create client, {0}")]
-    CreateClient(sozu_client::Error),
+    Serve(String, std::io::Error),
+    #[error("no address or socket to listen on is configured")]
+    NoListener,
+    #[error("failed to retrieve the sockets passed by systemd, {0}")]
+    Systemd(systemd::Error),
+    #[error("failed to load TLS configuration, {0}")]
+    Tls(tls::Error),
     #[error("failed to canonicalize path to command socket, {0}")]
-    CanonicalizeSocket(sozu_client::config::Error),
+    CanonicalizeSocket(Box<sozu_client::config::Error>),
+}
+
+// -----------------------------------------------------------------------------
+// Instance
+
+/// A Sōzu instance to scrape
+#[derive(Clone, Debug)]
+pub struct Instance {
+    pub name: String,
+    /// Path to the configuration of Sōzu
+    pub configuration: PathBuf,
+    /// Properties of the connection to the command socket of Sōzu, resolved
+    /// from its configuration
+    pub properties: ConnectionProperties,
+    pub connection: sozu::Connection,
+    /// Time given to Sōzu to answer a request
+    pub query_timeout: Duration,
+    /// Last metrics collected in background, if the collector is enabled
+    pub snapshot: Arc<RwLock<Option<Snapshot>>>,
+    /// Outcome of the last retrieval of the metrics of the instance
+    pub report: Arc<RwLock<Report>>,
+    /// Last inventory of the instance, retrieved in background
+    pub inventory: Arc<RwLock<Inventory>>,
+    /// Clusters of the instance retrieved so far
+    pub clusters: Clusters,
+    /// Expiration dates of the certificates of the instance read so far
+    pub expirations: Expirations,
+    /// Events pushed by the instance, forwarded to the clients of `/events`
+    pub events: broadcast::Sender<Event>,
+    /// Whether the instance has been removed or replaced by a reload of the
+    /// configuration, in which case its background tasks stop
+    pub retired: Arc<AtomicBool>,
+    /// Background tasks maintaining the connection to the instance and its
+    /// subscription to events
+    tasks: Arc<Mutex<Vec<AbortHandle>>>,
+}
+
+impl Instance {
+    fn new(sozu: Sozu, properties: ConnectionProperties) -> Self {
+        Self {
+            connection: sozu::Connection::new(sozu.name.to_owned(), properties.to_owned()),
+            query_timeout: sozu.query_timeout(),
+            name: sozu.name,
+            configuration: sozu.configuration,
+            properties,
+            snapshot: Arc::new(RwLock::new(None)),
+            report: Arc::new(RwLock::new(Report::default())),
+            inventory: Arc::new(RwLock::new(Inventory::default())),
+            clusters: Clusters::default(),
+            expirations: Expirations::default(),
+            events: broadcast::channel(events::CAPACITY).0,
+            retired: Arc::new(AtomicBool::new(false)),
+            tasks: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Returns whether the instance is configured by the given Sōzu table and
+    /// connection properties, in which case it survives a reload as is
+    fn matches(&self, sozu: &Sozu, properties: &ConnectionProperties) -> bool {
+        self.name == sozu.name
+            && self.configuration == sozu.configuration
+            && self.query_timeout == sozu.query_timeout()
+            && &self.properties == properties
+    }
+
+    /// Connect to the instance, whether or not it is up yet, subscribe to its
+    /// events and retrieve its inventory
+    fn start(&self) {
+        let mut tasks = self
+            .tasks
+            .lock()
+            .expect("tasks of the instance to not be poisoned");
+
+        tasks.push(tokio::spawn(self.connection.to_owned().maintain()).abort_handle());
+        tasks.push(tokio::spawn(events::listen(self.to_owned())).abort_handle());
+        tasks.push(tokio::spawn(inventory::refresh(self.to_owned())).abort_handle());
+    }
+
+    /// Stop the background tasks of the instance. Requests in flight keep
+    /// their connection until they complete.
+    fn retire(&self) {
+        self.retired.store(true, Ordering::Relaxed);
+        self.connection.retire();
+        for task in self
+            .tasks
+            .lock()
+            .expect("tasks of the instance to not be poisoned")
+            .drain(..)
+        {
+            task.abort();
+        }
+    }
+
+    /// Remove the series of the instance, once no instance of the same name
+    /// is scraped
+    fn forget(&self) {
+        sozu::forget(&self.name);
+        events::forget(&self.name);
+        collector::forget(&self.name);
+    }
+
+    pub fn is_retired(&self) -> bool {
+        self.retired.load(Ordering::Relaxed)
+    }
+
+    /// Returns the last inventory of the instance retrieved in background
+    pub fn inventory(&self) -> Inventory {
+        self.inventory
+            .read()
+            .expect("inventory lock to not be poisoned")
+            .to_owned()
+    }
+
+    /// Returns the deadline until which Sōzu is given to answer a request sent
+    /// now, after its query timeout or the given timeout if shorter
+    pub fn deadline(&self, timeout: Option<Duration>) -> Deadline {
+        Deadline::after(timeout.map_or(self.query_timeout, |timeout| {
+            timeout.min(self.query_timeout)
+        }))
+    }
 }
 
 // -----------------------------------------------------------------------------
@@ -43,62 +192,745 @@ pub enum Error {
 
 #[derive(Clone, Debug)]
 pub struct State {
-    pub client: Client,
     pub config: Arc<ConnectorConfiguration>,
+    pub instances: Arc<Vec<Instance>>,
+    /// Queries to Sōzu in flight, shared by concurrent scrapes
+    pub flights: Flights,
+    pub started_at: SystemTime,
+    /// Collectors of the metrics of the instances running in background
+    collectors: Arc<Vec<AbortHandle>>,
 }
 
 impl State {
-    fn new(client: Client, config: Arc<ConnectorConfiguration>) -> Self {
-        Self { client, config }
+    /// Create the state and start to collect the metrics of its instances in
+    /// background, if enabled
+    fn start(
+        config: Arc<ConnectorConfiguration>,
+        instances: Vec<Instance>,
+        started_at: SystemTime,
+    ) -> Self {
+        let mut collectors = vec![];
+        if let Some(collector) = &config.collector {
+            for instance in instances.iter() {
+                collectors.push(
+                    tokio::spawn(collector::collect(
+                        config.to_owned(),
+                        instance.to_owned(),
+                        collector.interval(),
+                    ))
+                    .abort_handle(),
+                );
+            }
+        }
+
+        Self {
+            config,
+            instances: Arc::new(instances),
+            flights: Flights::default(),
+            started_at,
+            collectors: Arc::new(collectors),
+        }
+    }
+
+    /// Returns the instance named `scope`, or all instances if none
+    pub fn instances<'a>(&'a self, scope: Option<&'a str>) -> impl Iterator<Item = &'a Instance> {
+        self.instances
+            .iter()
+            .filter(move |instance| scope.is_none_or(|name| name == instance.name))
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Shared
+
+/// The state of the server, swapped as a whole when the configuration is
+/// reloaded. Requests in flight keep the state they began with.
+#[derive(Clone, Debug)]
+pub struct Shared {
+    state: Arc<RwLock<State>>,
+    /// Whether the server is draining the requests in flight before exiting
+    draining: Arc<watch::Sender<bool>>,
+    /// Certificates served on the TCP listeners, if TLS is enabled
+    tls: Option<Tls>,
+}
+
+impl Shared {
+    /// Create the state of the server and start the background tasks of the
+    /// Sōzu instances
+    #[tracing::instrument(skip_all)]
+    pub fn new(
+        config: Arc<ConnectorConfiguration>,
+        sozu_configs: Vec<(Sozu, Arc<Config>)>,
+    ) -> Result<Self, Error> {
+        let tls = config
+            .tls
+            .as_ref()
+            .map(Tls::new)
+            .transpose()
+            .map_err(Error::Tls)?;
+        let (instances, _) = instances(sozu_configs, vec![])?;
+
+        Ok(Self {
+            state: Arc::new(RwLock::new(State::start(
+                config,
+                instances,
+                SystemTime::now(),
+            ))),
+            draining: Arc::new(watch::channel(false).0),
+            tls,
+        })
+    }
+
+    /// Returns the current state of the server
+    pub fn load(&self) -> State {
+        self.state
+            .read()
+            .expect("state of the server to not be poisoned")
+            .to_owned()
+    }
+
+    /// Mark the server as draining, from which point it is no longer ready
+    /// and the event streams end
+    pub fn drain(&self) {
+        self.draining.send_replace(true);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Completes once the server is draining
+    pub fn drained(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut draining = self.draining.subscribe();
+        async move {
+            let _ = draining.wait_for(|draining| *draining).await;
+        }
+    }
+
+    /// Stop the background tasks of every instance, so that the runtime does
+    /// not wait for them on exit
+    pub fn stop(&self) {
+        let state = self.load();
+        for collector in state.collectors.iter() {
+            collector.abort();
+        }
+        for instance in state.instances.iter() {
+            instance.retire();
+        }
+    }
+
+    /// Swap the state of the server for one built from the given
+    /// configuration. Instances whose configuration and command socket are
+    /// unchanged are kept along with their connection, the others are
+    /// replaced. The state is left untouched if the new one cannot be built.
+    #[tracing::instrument(skip_all)]
+    pub fn reload(
+        &self,
+        config: Arc<ConnectorConfiguration>,
+        sozu_configs: Vec<(Sozu, Arc<Config>)>,
+    ) -> Result<(), Error> {
+        let previous = self.load();
+        let server_config = match (&self.tls, &config.tls) {
+            (Some(_), Some(tls)) => Some(Tls::load(tls).map_err(Error::Tls)?),
+            _ => None,
+        };
+        let (instances, retired) = instances(sozu_configs, previous.instances.to_vec())?;
+
+        let changes = previous.config.diff(&config);
+        if changes.is_empty() {
+            info!("Connector configuration is unchanged");
+        }
+        for change in changes {
+            info!(change = change, "Connector configuration changed");
+        }
+
+        if previous.config.listeners() != config.listeners() {
+            warn!("Listeners are only changed on restart, keep listening on the previous ones");
+        }
+        if previous.config.sentry != config.sentry {
+            warn!("Sentry is only configured again on restart");
+        }
+        if self.tls.is_some() != config.tls.is_some() {
+            warn!("TLS is only enabled or disabled on restart");
+        }
+        if let (Some(tls), Some(server_config)) = (&self.tls, server_config) {
+            info!("Reload TLS certificates");
+            tls.store(server_config);
+        }
+
+        let state = State::start(config, instances, previous.started_at);
+        let previous = std::mem::replace(
+            &mut *self
+                .state
+                .write()
+                .expect("state of the server to not be poisoned"),
+            state,
+        );
+
+        for collector in previous.collectors.iter() {
+            collector.abort();
+        }
+        // an instance replaced by one of the same name hands its series over
+        let state = self.load();
+        for instance in retired {
+            info!(instance = instance.name, "Stop scraping Sōzu instance");
+            instance.retire();
+            if state.instances(Some(&instance.name)).next().is_none() {
+                instance.forget();
+            }
+        }
+
+        Ok(())
     }
 }
 
 // -----------------------------------------------------------------------------
 // helpers
 
-#[tracing::instrument(skip_all)]
-pub async fn serve(
-    config: Arc<ConnectorConfiguration>,
-    sozu_config: Arc<Config>,
-) -> Result<(), Error> {
-    // -------------------------------------------------------------------------
-    // Create client and state
-    info!("Create Sōzu client");
-    let mut opts = ConnectionProperties::from(&*sozu_config);
-    if opts.socket.is_relative() {
-        opts.socket = canonicalize_command_socket(&config.sozu.configuration, &sozu_config)
-            .map_err(Error::CanonicalizeSocket)?;
+/// Create the instances to scrape, reusing the given ones that are unchanged.
+/// Returns them along with the given instances that are not reused.
+fn instances(
+    sozu_configs: Vec<(Sozu, Arc<Config>)>,
+    mut previous: Vec<Instance>,
+) -> Result<(Vec<Instance>, Vec<Instance>), Error> {
+    let mut properties = Vec::with_capacity(sozu_configs.len());
+    for (sozu, sozu_config) in sozu_configs {
+        let mut opts = ConnectionProperties::from(&*sozu_config);
+        if opts.socket.is_relative() {
+            opts.socket = canonicalize_command_socket(&sozu.configuration, &sozu_config)
+                .map_err(|err| Error::CanonicalizeSocket(Box::new(err)))?;
+        }
+
+        debug!(
+            instance = sozu.name,
+            "Sōzu command socket is {:?}", opts.socket
+        );
+        properties.push((sozu, opts));
+    }
+
+    let mut instances = Vec::with_capacity(properties.len());
+    for (sozu, opts) in properties {
+        match previous
+            .iter()
+            .position(|instance| instance.matches(&sozu, &opts))
+        {
+            Some(index) => {
+                debug!(instance = sozu.name, "Keep Sōzu client");
+                instances.push(previous.swap_remove(index));
+            }
+            None => {
+                info!(instance = sozu.name, "Create Sōzu client");
+                let instance = Instance::new(sozu, opts);
+                instance.start();
+                instances.push(instance);
+            }
+        }
     }
 
-    debug!("Sōzu command socket is {:?}", opts.socket);
-    let client = Client::try_new(opts).await.map_err(Error::CreateClient)?;
-    let state = State::new(client, config.to_owned());
+    Ok((instances, previous))
+}
+
+/// Serve the routes until the `shutdown` future completes, then stop accepting
+/// connections and give the requests in flight the drain timeout to complete.
+/// `systemd` tells the sockets passed by socket activation, if any, and whether
+/// systemd expects notifications.
+#[tracing::instrument(skip_all)]
+pub async fn serve<F>(
+    shared: Shared,
+    systemd: systemd::Environment,
+    shutdown: F,
+) -> Result<(), Error>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let config = shared.load().config;
 
     // -------------------------------------------------------------------------
     // Create router
     let router = Router::new()
         .route("/healthz", get(handler::healthz))
         .route("/livez", get(handler::healthz))
-        .route("/readyz", get(handler::healthz))
-        .route("/status", get(handler::healthz))
+        .route("/readyz", get(handler::readyz))
+        .route("/status", get(handler::status))
         .route("/metrics", get(handler::telemetry))
-        .with_state(state)
+        .route("/metrics/{instance}", get(handler::instance_telemetry))
+        .route("/events", get(handler::events))
+        .with_state(shared.to_owned())
         .fallback(any(handler::not_found))
         .layer(middleware::from_fn(layer::access));
 
     // -------------------------------------------------------------------------
-    // Bind to listener and serve content
-    let listener = TcpListener::bind(&config.listening_address)
-        .await
-        .map_err(|err| Error::Bind(config.listening_address.to_owned(), err))?;
+    // Bind to listeners, unless systemd passes the sockets to listen on
+    let activated = systemd::listeners(&systemd).map_err(Error::Systemd)?;
+    let listeners = if activated.is_empty() {
+        config.listeners()
+    } else {
+        vec![]
+    };
+
+    let mut bound = Vec::with_capacity(activated.len() + listeners.len());
+    for (index, socket) in activated.into_iter().enumerate() {
+        let addr = format!("systemd:{index}");
+        info!(addr = addr, "Begin to listen on socket passed by systemd");
+        bound.push((addr, socket));
+    }
+    for listener in listeners.iter() {
+        let socket = listener::bind(listener).await.map_err(Error::Bind)?;
+        info!(addr = listener.to_string(), "Begin to listen on address");
+        bound.push((listener.to_string(), socket));
+    }
+
+    if bound.is_empty() {
+        return Err(Error::NoListener);
+    }
 
-    info!(
-        addr = config.listening_address.to_string(),
-        "Begin to listen on address"
-    );
-    axum::serve(listener, router.into_make_service())
+    tokio::spawn(systemd::supervise(shared.to_owned(), systemd.to_owned()));
+
+    // -------------------------------------------------------------------------
+    // Serve content on every listener, until they all stop accepting
+    // connections on shutdown and drain the requests in flight
+    let (stop, stopped) = watch::channel(false);
+    let servers = bound.into_iter().map(|(addr, bound)| {
+        let router = router.to_owned();
+        let tls = shared.tls.to_owned();
+        let mut stopped = stopped.to_owned();
+        let signal = async move {
+            let _ = stopped.wait_for(|stopped| *stopped).await;
+        };
+
+        async move {
+            match (bound, tls) {
+                (Bound::Tcp(listener), Some(tls)) => {
+                    axum::serve(tls.listen(listener), router.into_make_service())
+                        .with_graceful_shutdown(signal)
+                        .await
+                }
+                (Bound::Tcp(listener), None) => {
+                    axum::serve(listener, router.into_make_service())
+                        .with_graceful_shutdown(signal)
+                        .await
+                }
+                (Bound::Unix(listener), _) => {
+                    axum::serve(listener, router.into_make_service())
+                        .with_graceful_shutdown(signal)
+                        .await
+                }
+            }
+            .map_err(|err| Error::Serve(addr, err))
+        }
+    });
+
+    let drain = async {
+        shutdown.await;
+
+        let config = shared.load().config;
+        info!(
+            delay = config.drain_delay,
+            "Stop being ready, keep accepting connections for the drain delay"
+        );
+        systemd::stopping(&systemd);
+        shared.drain();
+        time::sleep(config.drain_delay()).await;
+
+        info!("Stop accepting connections and drain the requests in flight");
+        let _ = stop.send(true);
+        time::sleep(config.drain_timeout()).await;
+    };
+
+    let result = tokio::select! {
+        r = future::try_join_all(servers) => r.map(|_| ()),
+        _ = drain => {
+            warn!("Drain timeout reached, close the remaining connections");
+            Ok(())
+        }
+    };
+
+    for listener in listeners.iter() {
+        listener::unbind(listener);
+    }
+
+    result
+}
+
+#[cfg(test)]
+pub mod test {
+    //! Helpers to build the state of the server, for the tests of this module
+    //! and of the others
+
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+        path::Path,
+        time::Instant,
+    };
+
+    use axum::http::StatusCode;
+    use config::{File, FileFormat};
+    use sozu_command_lib::proto::command::{
+        request::RequestType, response_content::ContentType, AggregatedMetrics, QueryMetricsOptions,
+    };
+
+    use super::*;
+    use crate::svc::{
+        sozu::test::{connected, FakeSozu},
+        telemetry::prometheus::Format,
+    };
+
+    /// Parse the configuration of the connector from TOML
+    pub fn configuration(content: &str) -> Arc<ConnectorConfiguration> {
+        Arc::new(
+            config::Config::builder()
+                .add_source(File::from_str(content, FileFormat::Toml))
+                .build()
+                .and_then(config::Config::try_deserialize)
+                .expect("configuration to be valid"),
+        )
+    }
+
+    /// Create an instance listening on the given command socket, without
+    /// connecting to it
+    pub fn instance(name: &str, socket: PathBuf) -> Instance {
+        Instance::new(
+            Sozu {
+                name: name.to_owned(),
+                configuration: PathBuf::from(format!("/etc/sozu/{name}.toml")),
+                query_timeout: 1,
+            },
+            ConnectionProperties {
+                socket,
+                buffer_size: 16_384,
+                max_buffer_size: 163_840,
+            },
+        )
+    }
+
+    /// Create the state of the server, starting the collectors if configured
+    pub fn state(config: Arc<ConnectorConfiguration>, instances: Vec<Instance>) -> State {
+        State::start(config, instances, SystemTime::now())
+    }
+
+    /// Returns the configuration of a Sōzu instance whose command socket is
+    /// `socket`
+    fn sozu(name: &str, socket: &Path) -> (Sozu, Arc<Config>) {
+        (
+            Sozu {
+                name: name.to_owned(),
+                configuration: PathBuf::from(format!("/etc/sozu/{name}.toml")),
+                query_timeout: 1,
+            },
+            Arc::new(Config {
+                command_socket: socket.to_string_lossy().into_owned(),
+                command_buffer_size: 16_384,
+                max_command_buffer_size: 163_840,
+                ..Default::default()
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn isolate_unreachable_instances() {
+        let up = FakeSozu::answering("isolate-up", |request| match request {
+            RequestType::QueryMetrics(_) => {
+                Some(ContentType::Metrics(AggregatedMetrics::default()))
+            }
+            _ => None,
+        });
+
+        let config = configuration(
+            r#"
+[[sozu]]
+name = "up"
+configuration = "/etc/sozu/up.toml"
+
+[[sozu]]
+name = "down"
+configuration = "/etc/sozu/down.toml"
+"#,
+        );
+
+        // an instance that does not listen does not prevent the startup
+        let shared = Shared::new(
+            config,
+            vec![
+                sozu("up", &up.path),
+                sozu("down", Path::new("/nonexistent/down.sock")),
+            ],
+        )
+        .expect("server to start");
+        let state = shared.load();
+        assert!(connected(&state.instances[0].connection).await);
+
+        // nor does it blank the metrics of the others
+        let exposition = collector::fetch(
+            &state,
+            None,
+            QueryMetricsOptions::default(),
+            Format::Text,
+            None,
+        )
+        .await;
+        assert!(exposition.error.is_none());
+        assert!(exposition.body.contains(r#"sozu_up{instance="up"} 1"#));
+        assert!(exposition.body.contains(r#"sozu_up{instance="down"} 0"#));
+
+        // it is in error on its own
+        let exposition = collector::fetch(
+            &state,
+            Some("down"),
+            QueryMetricsOptions::default(),
+            Format::Text,
+            None,
+        )
+        .await;
+        assert!(exposition.error.is_some());
+
+        shared.stop();
+    }
+
+    #[tokio::test]
+    async fn probe_status_concurrently() {
+        let silent = (0..3)
+            .map(|index| FakeSozu::start(&format!("status-{index}"), Arc::new(|_| None)))
+            .collect::<Vec<_>>();
+
+        let shared = Shared::new(
+            configuration(
+                r#"
+[[sozu]]
+name = "status-0"
+configuration = "/etc/sozu/status-0.toml"
+
+[[sozu]]
+name = "status-1"
+configuration = "/etc/sozu/status-1.toml"
+
+[[sozu]]
+name = "status-2"
+configuration = "/etc/sozu/status-2.toml"
+"#,
+            ),
+            silent
+                .iter()
+                .enumerate()
+                .map(|(index, fake)| sozu(&format!("status-{index}"), &fake.path))
+                .collect(),
+        )
+        .expect("server to start");
+        for instance in shared.load().instances.iter() {
+            assert!(connected(&instance.connection).await);
+        }
+
+        // each instance is given its query timeout of a second, at once
+        let begin = Instant::now();
+        let response = handler::status(axum::extract::State(shared.to_owned())).await;
+        assert!(begin.elapsed() < Duration::from_secs(2));
+        assert_eq!(response.status(), StatusCode::OK);
+
+        shared.stop();
+    }
+
+    /// Returns whether a series labelled with the given instance is exported
+    fn exported(instance: &str) -> bool {
+        ::prometheus::gather()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .flat_map(|metric| metric.get_label())
+            .any(|label| label.name() == "instance" && label.value() == instance)
+    }
+
+    #[tokio::test]
+    async fn reload_instances() {
+        let kept = FakeSozu::answering("reload-kept", |_| None);
+        let replaced = FakeSozu::answering("reload-replaced", |_| None);
+        let moved = FakeSozu::answering("reload-moved", |_| None);
+
+        let shared = Shared::new(
+            configuration(
+                r#"
+[[sozu]]
+name = "reload-kept"
+configuration = "/etc/sozu/reload-kept.toml"
+
+[[sozu]]
+name = "reload-replaced"
+configuration = "/etc/sozu/reload-replaced.toml"
+
+[[sozu]]
+name = "reload-removed"
+configuration = "/etc/sozu/reload-removed.toml"
+"#,
+            ),
+            vec![
+                sozu("reload-kept", &kept.path),
+                sozu("reload-replaced", &replaced.path),
+                sozu(
+                    "reload-removed",
+                    Path::new("/nonexistent/reload-removed.sock"),
+                ),
+            ],
+        )
+        .expect("server to start");
+
+        let previous = shared.load();
+        for instance in previous.instances.iter().take(2) {
+            assert!(connected(&instance.connection).await);
+        }
+        collector::fetch(
+            &previous,
+            Some("reload-removed"),
+            QueryMetricsOptions::default(),
+            Format::Text,
+            None,
+        )
+        .await;
+        assert!(exported("reload-removed"));
+
+        shared
+            .reload(
+                configuration(
+                    r#"
+[[sozu]]
+name = "reload-kept"
+configuration = "/etc/sozu/reload-kept.toml"
+
+[[sozu]]
+name = "reload-replaced"
+configuration = "/etc/sozu/reload-replaced.toml"
+"#,
+                ),
+                vec![
+                    sozu("reload-kept", &kept.path),
+                    sozu("reload-replaced", &moved.path),
+                ],
+            )
+            .expect("configuration to be reloaded");
+
+        // the unchanged instance is reused along with its connection
+        let state = shared.load();
+        assert_eq!(2, state.instances.len());
+        assert!(Arc::ptr_eq(
+            &previous.instances[0].report,
+            &state.instances[0].report
+        ));
+        assert!(!state.instances[0].is_retired());
+        assert!(state.instances[0].connection.is_connected());
+
+        // the others are retired, and the series of the removed one forgotten
+        assert!(previous.instances[1].is_retired());
+        assert!(previous.instances[2].is_retired());
+        assert!(!exported("reload-removed"));
+
+        let replacement = &state.instances[1];
+        assert_eq!(moved.path, replacement.properties.socket);
+        assert!(!replacement.is_retired());
+        assert!(connected(&replacement.connection).await);
+        assert!(exported("reload-replaced"));
+
+        shared.stop();
+    }
+
+    /// Send a request for `path` on a unix socket and returns the response read
+    /// until `end` is reached or the connection is closed
+    async fn get(socket: &Path, path: &str, end: &'static str) -> String {
+        let socket = socket.to_owned();
+        let path = path.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let mut stream = UnixStream::connect(socket).expect("connector to accept");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .expect("read timeout to be set");
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .expect("request to be written");
+
+            let mut response = String::new();
+            let mut buf = [0; 1024];
+            while !response.ends_with(end) {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => response.push_str(&String::from_utf8_lossy(&buf[..read])),
+                }
+            }
+
+            response
+        })
         .await
-        .map_err(|err| Error::Serve(config.listening_address, err))?;
+        .expect("request to complete")
+    }
+
+    #[tokio::test]
+    async fn drain_requests() {
+        let fake = FakeSozu::answering("drain", |request| match request {
+            RequestType::ListWorkers(_) => Some(ContentType::Workers(Default::default())),
+            _ => None,
+        });
+        let socket = std::env::temp_dir().join(format!(
+            "sozu-prometheus-connector-drain-{}.sock",
+            std::process::id()
+        ));
+
+        let config = configuration(&format!(
+            r#"
+drain-delay = 1
+drain-timeout = 5
+
+[[listeners]]
+path = "{}"
+
+[[sozu]]
+name = "drain"
+configuration = "/etc/sozu/drain.toml"
+"#,
+            socket.display()
+        ));
 
-    Ok(())
+        let shared = Shared::new(config, vec![sozu("drain", &fake.path)]).expect("server to start");
+        assert!(connected(&shared.load().instances[0].connection).await);
+
+        let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            shared.to_owned(),
+            systemd::Environment::default(),
+            async move {
+                let _ = signal.await;
+            },
+        ));
+        while !socket.exists() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert!(get(&socket, "/readyz", "}")
+            .await
+            .starts_with("HTTP/1.1 200"));
+
+        // an event stream ends once the server drains
+        let events = tokio::spawn({
+            let socket = socket.to_owned();
+            async move { get(&socket, "/events", "0\r\n\r\n").await }
+        });
+        time::sleep(Duration::from_millis(100)).await;
+        let begin = std::time::Instant::now();
+        shutdown.send(()).expect("server to run");
+
+        let events = events.await.expect("event stream to be read");
+        assert!(events.starts_with("HTTP/1.1 200"));
+        assert!(events.ends_with("0\r\n\r\n"));
+        assert!(begin.elapsed() < Duration::from_secs(1));
+
+        // connections are still accepted during the drain delay, while the
+        // server is no longer ready
+        assert!(get(&socket, "/readyz", "}")
+            .await
+            .starts_with("HTTP/1.1 503"));
+
+        // then the server stops before the drain timeout
+        time::timeout(Duration::from_secs(3), server)
+            .await
+            .expect("server to stop before the drain timeout")
+            .expect("server to not panic")
+            .expect("server to stop gracefully");
+        assert!(begin.elapsed() >= Duration::from_secs(1));
+        assert!(!socket.exists());
+
+        shared.stop();
+    }
 }
@@ -2,7 +2,7 @@
 //!
 //! This module provides logging facilities and helpers
 
-use std::borrow::Cow;
+use std::{borrow::Cow, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use tracing::Level;
@@ -50,10 +50,19 @@ impl SentryContext {
 
 #[derive(Default)]
 pub struct LoggingInitGuard {
-    #[allow(dead_code)]
     sentry_guard: Option<sentry::ClientInitGuard>,
 }
 
+impl LoggingInitGuard {
+    /// Send the events waiting to be forwarded to sentry, waiting at most for
+    /// the given timeout. Returns whether all of them have been sent.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        self.sentry_guard
+            .as_ref()
+            .is_none_or(|guard| guard.flush(Some(timeout)))
+    }
+}
+
 impl From<Option<sentry::ClientInitGuard>> for LoggingInitGuard {
     #[tracing::instrument(skip_all)]
     fn from(sentry_guard: Option<sentry::ClientInitGuard>) -> Self {
@@ -5,4 +5,6 @@
 pub mod config;
 pub mod http;
 pub mod logging;
+pub mod sozu;
+pub mod systemd;
 pub mod telemetry;
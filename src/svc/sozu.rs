@@ -0,0 +1,893 @@
+//! # Sōzu module
+//!
+//! This module provides a connection to the command socket of Sōzu that is
+//! established again whenever it is lost, and helpers to send requests on it,
+//! bounded by a deadline.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use bb8::ManageConnection;
+use nix::sys::{
+    socket::{setsockopt, sockopt::SendTimeout},
+    time::{TimeVal, TimeValLike},
+};
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use sozu_client::channel::{ConnectionManager, ConnectionProperties};
+use sozu_command_lib::{
+    channel::{Channel, ChannelError},
+    proto::command::{
+        request::RequestType, response_content::ContentType, CertificateAndKey,
+        CertificatesByAddress, ClusterInformation, FrontendFilters, ListListeners, ListWorkers,
+        ListedFrontends, ListenersList, QueryCertificatesFilters, QueryClustersHashes, Request,
+        Response, ResponseContent, ResponseStatus, Status, SubscribeEvents, WorkerInfo,
+    },
+};
+use tokio::{
+    sync::Notify,
+    task::{self, JoinError},
+    time,
+};
+use tracing::{debug, info, warn};
+
+// -----------------------------------------------------------------------------
+// Constants
+
+/// Time given to Sōzu to accept a connection on its command socket
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time to wait after a first failure to connect to Sōzu, doubled after each
+/// failure
+pub const MIN_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Maximum time to wait between two attempts to connect to Sōzu
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// -----------------------------------------------------------------------------
+// Telemetry
+
+static CONNECTED: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "sozu_connected",
+        "Whether the connector is connected to the command socket of Sōzu, 1 if connected, 0 otherwise",
+        &["instance"]
+    )
+    .expect("'sozu_connected' to not be already registered")
+});
+
+static RECONNECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "sozu_reconnections_total",
+        "Number of times the connector connected again to the command socket of Sōzu after losing it",
+        &["instance"]
+    )
+    .expect("'sozu_reconnections_total' to not be already registered")
+});
+
+// -----------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to send request to Sōzu, {0}")]
+    Send(Box<sozu_client::Error>),
+    #[error("Sōzu did not answer within {}ms", .0.as_millis())]
+    Timeout(Duration),
+    #[error("got an unexpected response from Sōzu, with status {0}")]
+    InvalidResponse(i32),
+    #[error("failed to connect to Sōzu on its command socket, {0}")]
+    Connect(Box<sozu_client::channel::Error>),
+    #[error("failed to write request to Sōzu, {0}")]
+    Write(ChannelError),
+    #[error("failed to execute blocking task, {0}")]
+    Join(JoinError),
+    #[error("not connected to Sōzu on its command socket")]
+    Disconnected,
+}
+
+// -----------------------------------------------------------------------------
+// Deadline
+
+/// The instant until which Sōzu is given to answer, along with the time it
+/// was given, which is reported when it does not answer in time
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Deadline {
+    pub at: Instant,
+    pub budget: Duration,
+}
+
+impl Deadline {
+    /// Give Sōzu `budget` to answer from now on
+    pub fn after(budget: Duration) -> Self {
+        Self {
+            at: Instant::now() + budget,
+            budget,
+        }
+    }
+
+    /// Returns the time left until the deadline
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Connection
+
+/// A channel to the command socket of Sōzu
+type SozuChannel = Channel<Request, Response>;
+
+/// The idle channels to the command socket of Sōzu, reused by the next
+/// requests
+type Pool = Arc<Mutex<Vec<SozuChannel>>>;
+
+/// A client of the command socket of a Sōzu instance. The client is created
+/// again whenever Sōzu cannot be reached through it, e.g. once Sōzu restarted
+/// or upgraded its main process.
+#[derive(Clone, Debug)]
+pub struct Connection {
+    instance: String,
+    properties: ConnectionProperties,
+    /// The idle channels along with their generation, none while Sōzu is not
+    /// reachable
+    pool: Arc<RwLock<Option<(u64, Pool)>>>,
+    /// Notified when the client has to be created again
+    lost: Arc<Notify>,
+    /// Whether the instance is no longer scraped, in which case the gauge of
+    /// its connection is left to the instance replacing it, if any
+    retired: Arc<AtomicBool>,
+}
+
+impl Connection {
+    /// Create a connection to the command socket of Sōzu, which is established
+    /// by [`Connection::maintain`]
+    pub fn new(instance: String, properties: ConnectionProperties) -> Self {
+        CONNECTED.with_label_values(&[&instance]).set(0);
+
+        let lost = Arc::new(Notify::new());
+        lost.notify_one();
+
+        Self {
+            instance,
+            properties,
+            pool: Arc::new(RwLock::new(None)),
+            lost,
+            retired: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Stop reporting the state of the connection, requests in flight keep
+    /// their channel until they complete
+    pub fn retire(&self) {
+        self.retired.store(true, Ordering::Relaxed);
+    }
+
+    /// Set the gauge of the connection, unless it is retired
+    fn report(&self, connected: bool) {
+        if !self.retired.load(Ordering::Relaxed) {
+            CONNECTED
+                .with_label_values(&[&self.instance])
+                .set(connected.into());
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.pool
+            .read()
+            .expect("pool lock to not be poisoned")
+            .is_some()
+    }
+
+    /// Connect to Sōzu, and connect again whenever the connection is lost,
+    /// waiting longer after each failed attempt
+    #[tracing::instrument(skip_all, fields(instance = self.instance))]
+    pub async fn maintain(self) {
+        let mut generation = 0;
+        loop {
+            self.lost.notified().await;
+
+            let mut backoff = MIN_BACKOFF;
+            let channel = loop {
+                match self.connect(CONNECT_TIMEOUT).await {
+                    Ok(channel) => break channel,
+                    Err(err) => {
+                        warn!(
+                            error = err.to_string(),
+                            "Could not connect to the command socket of Sōzu, retry in {}ms",
+                            backoff.as_millis()
+                        );
+
+                        time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+            };
+
+            generation += 1;
+            *self.pool.write().expect("pool lock to not be poisoned") =
+                Some((generation, Arc::new(Mutex::new(vec![channel]))));
+
+            info!("Connected to the command socket of Sōzu");
+            self.report(true);
+            if generation > 1 {
+                RECONNECTIONS.with_label_values(&[&self.instance]).inc();
+            }
+        }
+    }
+
+    /// Open a channel to the command socket of Sōzu, which has `timeout` to
+    /// accept it
+    async fn connect(&self, timeout: Duration) -> Result<SozuChannel, Error> {
+        time::timeout(
+            timeout,
+            ConnectionManager::new(self.properties.to_owned()).connect(),
+        )
+        .await
+        .map_err(|_| Error::Timeout(timeout))?
+        .map_err(|err| Error::Connect(Box::new(err)))
+    }
+
+    /// Send a request to Sōzu and wait for its response, until `deadline`.
+    /// Each request is sent on its own channel, taken from the idle ones or
+    /// opened on demand. A channel on which Sōzu did not read the request or
+    /// answer in time is dropped, as it may still receive the late response,
+    /// while the client is created again only if Sōzu could not be reached.
+    #[tracing::instrument(skip(self))]
+    pub async fn send(&self, request: RequestType, deadline: Deadline) -> Result<Response, Error> {
+        let (generation, pool) = self
+            .pool
+            .read()
+            .expect("pool lock to not be poisoned")
+            .to_owned()
+            .ok_or(Error::Disconnected)?;
+
+        // the request is not sent at all once the deadline is reached
+        let timeout = deadline.remaining();
+        if timeout.is_zero() {
+            return Err(Error::Timeout(deadline.budget));
+        }
+
+        let idle = pool.lock().expect("pool lock to not be poisoned").pop();
+        let channel = match idle {
+            Some(channel) => channel,
+            None => {
+                debug!("Open another channel to the command socket of Sōzu");
+                self.connect(timeout.min(CONNECT_TIMEOUT))
+                    .await
+                    .inspect_err(|_| self.lose(generation))?
+            }
+        };
+
+        // the channel is blocking, so the exchange happens on a dedicated thread
+        let request = Request {
+            request_type: Some(request),
+        };
+        let (channel, result) = task::spawn_blocking(move || {
+            let mut channel = channel;
+            let result = exchange(&mut channel, &request, timeout);
+            (channel, result)
+        })
+        .await
+        .map_err(Error::Join)?;
+
+        match result {
+            Ok(response) => {
+                pool.lock()
+                    .expect("pool lock to not be poisoned")
+                    .push(channel);
+                Ok(response)
+            }
+            Err(err) => match err.as_ref() {
+                sozu_client::Error::Receive(ChannelError::TimeoutReached(_)) => {
+                    debug!("Drop the channel on which Sōzu did not answer in time");
+                    Err(Error::Timeout(deadline.budget))
+                }
+                _ if err.is_recoverable() => {
+                    pool.lock()
+                        .expect("pool lock to not be poisoned")
+                        .push(channel);
+                    Err(Error::Send(err))
+                }
+                _ => {
+                    self.lose(generation);
+                    Err(Error::Send(err))
+                }
+            },
+        }
+    }
+
+    /// Drop the client of the given generation, so that it is created again
+    fn lose(&self, generation: u64) {
+        let mut pool = self.pool.write().expect("pool lock to not be poisoned");
+        if pool
+            .as_ref()
+            .is_some_and(|(current, _)| *current == generation)
+        {
+            warn!(
+                instance = self.instance,
+                "Lost the connection to the command socket of Sōzu"
+            );
+
+            *pool = None;
+            self.report(false);
+            self.lost.notify_one();
+        }
+    }
+}
+
+/// Write the request on the channel and read its response, skipping the
+/// responses telling that it is being processed, for at most `timeout`. A
+/// request that Sōzu does not read in time is left partially written, so that
+/// its response is never received and the channel is dropped.
+fn exchange(
+    channel: &mut SozuChannel,
+    request: &Request,
+    timeout: Duration,
+) -> Result<Response, Box<sozu_client::Error>> {
+    let begin = Instant::now();
+
+    // a zero send timeout blocks forever
+    let send_timeout = TimeVal::microseconds(
+        i64::try_from(timeout.max(Duration::from_millis(1)).as_micros()).unwrap_or(i64::MAX),
+    );
+    setsockopt(&channel.sock, SendTimeout, &send_timeout).map_err(|err| {
+        Box::new(sozu_client::Error::Send(ChannelError::SetTimeout {
+            fd: channel.fd(),
+            error: err.to_string(),
+        }))
+    })?;
+    channel
+        .write_message(request)
+        .map_err(|err| Box::new(sozu_client::Error::Send(err)))?;
+
+    loop {
+        let timeout = Some(timeout.saturating_sub(begin.elapsed()));
+        let response = channel
+            .read_message_blocking_timeout(timeout)
+            .map_err(|err| Box::new(sozu_client::Error::Receive(err)))?;
+
+        match ResponseStatus::try_from(response.status) {
+            Ok(ResponseStatus::Processing) => continue,
+            Ok(ResponseStatus::Ok) => return Ok(response),
+            Ok(ResponseStatus::Failure) => {
+                return Err(Box::new(sozu_client::Error::Failure(
+                    ResponseStatus::Failure.as_str_name().to_owned(),
+                    response.message.to_lowercase(),
+                    response,
+                )))
+            }
+            Err(_) => {
+                return Err(Box::new(sozu_client::Error::InvalidStatusCode(
+                    response.status,
+                )))
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// helpers
+
+/// Send a request to Sōzu and returns the status and the content of its
+/// response, which has to be received until `deadline`
+#[tracing::instrument(skip(connection))]
+pub async fn send(
+    connection: &Connection,
+    request: RequestType,
+    deadline: Deadline,
+) -> Result<(i32, Option<ContentType>), Error> {
+    let response = connection.send(request, deadline).await?;
+
+    Ok((
+        response.status,
+        response
+            .content
+            .and_then(|ResponseContent { content_type }| content_type),
+    ))
+}
+
+/// Remove the series of the connection to an instance that is no longer
+/// scraped
+pub fn forget(instance: &str) {
+    let _ = CONNECTED.remove_label_values(&[instance]);
+    let _ = RECONNECTIONS.remove_label_values(&[instance]);
+}
+
+/// Retrieve the workers of Sōzu, a cheap round-trip answered by its main
+/// process
+#[tracing::instrument(skip(connection))]
+pub async fn list_workers(
+    connection: &Connection,
+    deadline: Deadline,
+) -> Result<Vec<WorkerInfo>, Error> {
+    match send(
+        connection,
+        RequestType::ListWorkers(ListWorkers {}),
+        deadline,
+    )
+    .await?
+    {
+        (_, Some(ContentType::Workers(workers))) => Ok(workers.vec),
+        (status, _) => Err(Error::InvalidResponse(status)),
+    }
+}
+
+/// Retrieve the workers of Sōzu along with their run state, as reported by the
+/// main process after asking each of them for its status
+#[tracing::instrument(skip(connection))]
+pub async fn status(connection: &Connection, deadline: Deadline) -> Result<Vec<WorkerInfo>, Error> {
+    match send(connection, RequestType::Status(Status {}), deadline).await? {
+        (_, Some(ContentType::Workers(workers))) => Ok(workers.vec),
+        (status, _) => Err(Error::InvalidResponse(status)),
+    }
+}
+
+/// Retrieve the certificates of the state of Sōzu, keyed by fingerprint
+#[tracing::instrument(skip(connection))]
+pub async fn certificates(
+    connection: &Connection,
+    deadline: Deadline,
+) -> Result<BTreeMap<String, CertificateAndKey>, Error> {
+    let request = RequestType::QueryCertificatesFromTheState(QueryCertificatesFilters::default());
+    match send(connection, request, deadline).await? {
+        (_, Some(ContentType::CertificatesWithFingerprints(certificates))) => {
+            Ok(certificates.certs)
+        }
+        (status, _) => Err(Error::InvalidResponse(status)),
+    }
+}
+
+/// Retrieve the summaries of the certificates served by the workers of Sōzu,
+/// grouped by listener. A certificate served by several workers is listed once
+/// per worker.
+#[tracing::instrument(skip(connection))]
+pub async fn served_certificates(
+    connection: &Connection,
+    deadline: Deadline,
+) -> Result<Vec<CertificatesByAddress>, Error> {
+    let request = RequestType::QueryCertificatesFromWorkers(QueryCertificatesFilters::default());
+    match send(connection, request, deadline).await? {
+        (_, Some(ContentType::WorkerResponses(responses))) => Ok(responses
+            .map
+            .into_values()
+            .filter_map(|ResponseContent { content_type }| match content_type {
+                Some(ContentType::CertificatesByAddress(list)) => Some(list.certificates),
+                _ => None,
+            })
+            .flatten()
+            .collect()),
+        (status, _) => Err(Error::InvalidResponse(status)),
+    }
+}
+
+/// Retrieve the frontends of the state of Sōzu, whatever their protocol
+#[tracing::instrument(skip(connection))]
+pub async fn frontends(
+    connection: &Connection,
+    deadline: Deadline,
+) -> Result<ListedFrontends, Error> {
+    let request = RequestType::ListFrontends(FrontendFilters::default());
+    match send(connection, request, deadline).await? {
+        (_, Some(ContentType::FrontendList(frontends))) => Ok(frontends),
+        (status, _) => Err(Error::InvalidResponse(status)),
+    }
+}
+
+/// Retrieve the listeners of the state of Sōzu, keyed by address
+#[tracing::instrument(skip(connection))]
+pub async fn listeners(
+    connection: &Connection,
+    deadline: Deadline,
+) -> Result<ListenersList, Error> {
+    match send(
+        connection,
+        RequestType::ListListeners(ListListeners {}),
+        deadline,
+    )
+    .await?
+    {
+        (_, Some(ContentType::ListenersList(listeners))) => Ok(listeners),
+        (status, _) => Err(Error::InvalidResponse(status)),
+    }
+}
+
+/// Retrieve the identifiers of the clusters of Sōzu along with a hash of their
+/// configuration, which changes whenever the cluster does
+#[tracing::instrument(skip(connection))]
+pub async fn cluster_hashes(
+    connection: &Connection,
+    deadline: Deadline,
+) -> Result<BTreeMap<String, u64>, Error> {
+    let request = RequestType::QueryClustersHashes(QueryClustersHashes {});
+    match send(connection, request, deadline).await? {
+        (status, Some(content)) => match main_content(content) {
+            Some(ContentType::ClusterHashes(hashes)) => Ok(hashes.map),
+            _ => Err(Error::InvalidResponse(status)),
+        },
+        (status, None) => Err(Error::InvalidResponse(status)),
+    }
+}
+
+/// Retrieve the configuration, frontends and backends of a cluster of Sōzu, if
+/// it exists
+#[tracing::instrument(skip(connection))]
+pub async fn cluster(
+    connection: &Connection,
+    cluster_id: &str,
+    deadline: Deadline,
+) -> Result<Option<ClusterInformation>, Error> {
+    let request = RequestType::QueryClusterById(cluster_id.to_owned());
+    match send(connection, request, deadline).await? {
+        (status, Some(content)) => match main_content(content) {
+            Some(ContentType::Clusters(clusters)) => Ok(clusters.vec.into_iter().next()),
+            _ => Err(Error::InvalidResponse(status)),
+        },
+        (status, None) => Err(Error::InvalidResponse(status)),
+    }
+}
+
+/// Returns the content answered by the main process of Sōzu, cluster queries
+/// are answered either by the main process alone or along with each worker
+fn main_content(content: ContentType) -> Option<ContentType> {
+    match content {
+        ContentType::WorkerResponses(mut responses) => responses
+            .map
+            .remove("main")
+            .or_else(|| responses.map.into_values().next())
+            .and_then(|ResponseContent { content_type }| content_type),
+        content => Some(content),
+    }
+}
+
+/// Open a dedicated connection to the command socket of Sōzu and subscribe to
+/// its events, which Sōzu then pushes on this connection as they happen
+#[tracing::instrument]
+pub async fn subscribe(
+    properties: &ConnectionProperties,
+) -> Result<Channel<Request, Response>, Error> {
+    let mut channel = ConnectionManager::new(properties.to_owned())
+        .connect()
+        .await
+        .map_err(|err| Error::Connect(Box::new(err)))?;
+
+    channel
+        .write_message(&Request {
+            request_type: Some(RequestType::SubscribeEvents(SubscribeEvents {})),
+        })
+        .map_err(Error::Write)?;
+
+    Ok(channel)
+}
+
+#[cfg(test)]
+pub mod test {
+    //! A fake Sōzu answering on its command socket, for the tests of this
+    //! module and of the others
+
+    use std::{
+        io::{Read, Write},
+        os::unix::net::{UnixListener, UnixStream},
+        path::PathBuf,
+        thread,
+    };
+
+    use prometheus::core::Collector;
+    use prost::Message;
+
+    use super::*;
+
+    /// How the fake Sōzu answers a request: after a delay with a response, or
+    /// never if none
+    pub type Answer = Arc<dyn Fn(&RequestType) -> Option<(Duration, Response)> + Send + Sync>;
+
+    /// A fake Sōzu listening on a unix socket, which records the requests it
+    /// receives
+    pub struct FakeSozu {
+        pub path: PathBuf,
+        requests: Arc<Mutex<Vec<RequestType>>>,
+    }
+
+    impl FakeSozu {
+        /// Listen on a command socket named after `name`, answering each
+        /// request with `answer`
+        pub fn start(name: &str, answer: Answer) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "sozu-prometheus-connector-fake-{name}-{}.sock",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path).expect("socket to be bound");
+            let requests: Arc<Mutex<Vec<RequestType>>> = Arc::default();
+
+            let recorded = requests.to_owned();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else {
+                        return;
+                    };
+
+                    let answer = answer.to_owned();
+                    let recorded = recorded.to_owned();
+                    thread::spawn(move || serve(stream, answer, recorded));
+                }
+            });
+
+            Self { path, requests }
+        }
+
+        /// Answer every request successfully with the content built from it
+        pub fn answering(
+            name: &str,
+            content: impl Fn(&RequestType) -> Option<ContentType> + Send + Sync + 'static,
+        ) -> Self {
+            Self::start(
+                name,
+                Arc::new(move |request| Some((Duration::ZERO, response(content(request))))),
+            )
+        }
+
+        pub fn properties(&self) -> ConnectionProperties {
+            ConnectionProperties {
+                socket: self.path.to_owned(),
+                buffer_size: 16_384,
+                max_buffer_size: 163_840,
+            }
+        }
+
+        /// Returns the number of requests received that match
+        pub fn received(&self, matches: impl Fn(&RequestType) -> bool) -> usize {
+            self.requests
+                .lock()
+                .expect("requests lock to not be poisoned")
+                .iter()
+                .filter(|request| matches(request))
+                .count()
+        }
+    }
+
+    impl Drop for FakeSozu {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    /// Returns a successful response with the given content
+    pub fn response(content: Option<ContentType>) -> Response {
+        Response {
+            status: ResponseStatus::Ok as i32,
+            content: content.map(|content| ResponseContent {
+                content_type: Some(content),
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Read the requests sent on a connection and answer them, each message is
+    /// prefixed by its length, prefix included
+    fn serve(mut stream: UnixStream, answer: Answer, requests: Arc<Mutex<Vec<RequestType>>>) {
+        loop {
+            let mut prefix = [0; 8];
+            if stream.read_exact(&mut prefix).is_err() {
+                return;
+            }
+
+            let mut payload = vec![0; (u64::from_le_bytes(prefix) - 8) as usize];
+            if stream.read_exact(&mut payload).is_err() {
+                return;
+            }
+
+            let Some(request) = Request::decode(payload.as_slice())
+                .ok()
+                .and_then(|request| request.request_type)
+            else {
+                return;
+            };
+
+            requests
+                .lock()
+                .expect("requests lock to not be poisoned")
+                .push(request.to_owned());
+
+            let Some((delay, response)) = answer(&request) else {
+                continue;
+            };
+
+            thread::sleep(delay);
+            let payload = response.encode_to_vec();
+            let mut message = ((payload.len() + 8) as u64).to_le_bytes().to_vec();
+            message.extend(payload);
+            if stream.write_all(&message).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Wait for the connection to be established, for at most five seconds
+    pub async fn connected(connection: &Connection) -> bool {
+        let connected = async {
+            while !connection.is_connected() {
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+
+        time::timeout(Duration::from_secs(5), connected)
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn connect_once_sozu_listens() {
+        let socket = std::env::temp_dir().join(format!("sozu-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+
+        let connection = Connection::new(
+            "connect-once-sozu-listens".to_owned(),
+            ConnectionProperties {
+                socket: socket.to_owned(),
+                buffer_size: 16_384,
+                max_buffer_size: 163_840,
+            },
+        );
+
+        assert!(matches!(
+            connection
+                .send(
+                    RequestType::ListWorkers(ListWorkers {}),
+                    Deadline::after(Duration::from_secs(1))
+                )
+                .await,
+            Err(Error::Disconnected)
+        ));
+
+        tokio::spawn(connection.to_owned().maintain());
+        time::sleep(CONNECT_TIMEOUT + MIN_BACKOFF / 2).await;
+        assert!(!connection.is_connected());
+
+        let _listener = UnixListener::bind(&socket).expect("socket to be bound");
+        assert!(connected(&connection).await);
+
+        // a lost connection is established again
+        connection.lose(1);
+        assert!(!connection.is_connected());
+        assert!(connected(&connection).await);
+        assert_eq!(
+            RECONNECTIONS
+                .with_label_values(&["connect-once-sozu-listens"])
+                .get(),
+            1
+        );
+
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[tokio::test]
+    async fn drop_channel_on_timeout() {
+        let sozu = FakeSozu::start(
+            "drop-channel-on-timeout",
+            Arc::new(|request| {
+                let delay = match request {
+                    RequestType::Status(_) => Duration::from_millis(500),
+                    _ => Duration::ZERO,
+                };
+
+                Some((delay, response(None)))
+            }),
+        );
+
+        let connection = Connection::new("drop-channel-on-timeout".to_owned(), sozu.properties());
+        tokio::spawn(connection.to_owned().maintain());
+        assert!(connected(&connection).await);
+
+        // a slow answer only drops the channel it is expected on
+        let budget = Duration::from_millis(100);
+        let slow = connection.send(RequestType::Status(Status {}), Deadline::after(budget));
+        let fast = connection.send(
+            RequestType::ListWorkers(ListWorkers {}),
+            Deadline::after(Duration::from_secs(1)),
+        );
+        let (slow, fast) = tokio::join!(slow, fast);
+
+        assert!(matches!(slow, Err(Error::Timeout(timeout)) if timeout == budget));
+        assert!(fast.is_ok());
+        assert!(connection.is_connected());
+
+        // the late answer is not read as the answer of the next request
+        time::sleep(Duration::from_millis(500)).await;
+        let response = connection
+            .send(
+                RequestType::ListWorkers(ListWorkers {}),
+                Deadline::after(Duration::from_secs(1)),
+            )
+            .await;
+        assert!(response.is_ok());
+        assert_eq!(
+            RECONNECTIONS
+                .with_label_values(&["drop-channel-on-timeout"])
+                .get(),
+            0
+        );
+
+        // a deadline already reached reports the time that was given
+        let deadline = Deadline {
+            at: Instant::now(),
+            budget,
+        };
+        assert!(matches!(
+            connection
+                .send(RequestType::ListWorkers(ListWorkers {}), deadline)
+                .await,
+            Err(Error::Timeout(timeout)) if timeout == budget
+        ));
+    }
+
+    #[test]
+    fn bound_write_to_sozu() {
+        let socket = std::env::temp_dir().join(format!(
+            "sozu-prometheus-connector-bound-write-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).expect("socket to be bound");
+
+        let mut channel = SozuChannel::from_path(&socket.to_string_lossy(), 16_384, 163_840)
+            .expect("channel to be connected");
+        channel.blocking().expect("channel to be blocking");
+        setsockopt(&channel.sock, nix::sys::socket::sockopt::SndBuf, &4_096)
+            .expect("send buffer to be set");
+
+        // a Sōzu that accepts the connection but never reads the request
+        let (_stream, _) = listener.accept().expect("connection to be accepted");
+        let request = Request {
+            request_type: Some(RequestType::QueryClusterById("a".repeat(100_000))),
+        };
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(exchange(&mut channel, &request, Duration::from_millis(200)));
+        });
+
+        let result = rx
+            .recv_timeout(Duration::from_secs(2))
+            .expect("exchange to complete in time");
+        assert!(matches!(
+            result.map_err(|err| *err),
+            Err(sozu_client::Error::Receive(ChannelError::TimeoutReached(_)))
+        ));
+
+        std::fs::remove_file(&socket).expect("socket to be removed");
+    }
+
+    #[tokio::test]
+    async fn leave_gauge_to_replacement() {
+        let sozu = FakeSozu::answering("leave-gauge", |_| None);
+        let gauge = || CONNECTED.with_label_values(&["leave-gauge"]).get();
+
+        let retired = Connection::new("leave-gauge".to_owned(), sozu.properties());
+        tokio::spawn(retired.to_owned().maintain());
+        assert!(connected(&retired).await);
+
+        let replacement = Connection::new("leave-gauge".to_owned(), sozu.properties());
+        tokio::spawn(replacement.to_owned().maintain());
+        assert!(connected(&replacement).await);
+        assert_eq!(1, gauge());
+
+        // a retired connection that loses Sōzu does not report it
+        retired.retire();
+        retired.lose(1);
+        assert!(!retired.is_connected());
+        assert_eq!(1, gauge());
+
+        forget("leave-gauge");
+        assert!(CONNECTED
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .all(|metric| metric.get_label()[0].value() != "leave-gauge"));
+    }
+}
@@ -0,0 +1,319 @@
+//! # Systemd module
+//!
+//! This module provides the integration with systemd: the sockets passed by
+//! socket activation, the notifications of the state of the service and the
+//! pings of its watchdog.
+
+use std::{
+    env,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
+
+use futures_util::future;
+use nix::sys::socket::{
+    getsockname, getsockopt, sockopt, AddressFamily, SockType, SockaddrLike, SockaddrStorage,
+};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    time,
+};
+use tracing::{debug, info, warn};
+
+use crate::svc::{
+    http::server::{listener::Bound, Shared},
+    sozu,
+};
+
+// -----------------------------------------------------------------------------
+// Constants
+
+/// First file descriptor passed by socket activation
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// Interval between two checks that Sōzu is connected, before notifying
+/// systemd that the connector is ready
+pub const READY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time given to the Sōzu instances to be connected before notifying systemd
+/// that the connector is ready anyway, so that a Sōzu down at boot does not
+/// make the start of the connector time out
+pub const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// -----------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to parse environment variable '{0}', got '{1}'")]
+    Parse(&'static str, String),
+    #[error("failed to retrieve the address of socket {0}, {1}")]
+    SocketAddress(RawFd, nix::Error),
+    #[error("socket {0} is neither a TCP nor a unix socket")]
+    UnsupportedSocket(RawFd),
+    #[error("socket {0} is not a listening stream socket")]
+    NotListening(RawFd),
+    #[error("failed to set up socket {0}, {1}")]
+    Socket(RawFd, std::io::Error),
+    #[error("failed to send notification to systemd, {0}")]
+    Notify(std::io::Error),
+}
+
+// -----------------------------------------------------------------------------
+// Environment
+
+/// What systemd tells the connector through its environment
+#[derive(Clone, Debug, Default)]
+pub struct Environment {
+    /// Number of listening sockets passed by socket activation
+    pub listen_fds: Option<String>,
+    /// Socket on which systemd expects notifications
+    pub notify_socket: Option<PathBuf>,
+    /// Interval at which systemd expects the watchdog to be pinged, in
+    /// microseconds
+    pub watchdog_usec: Option<String>,
+}
+
+impl Environment {
+    /// Read the variables set by systemd for this process, and remove those
+    /// of socket activation so that they are not inherited. As it modifies the
+    /// environment, it must be called before any thread is spawned.
+    pub fn take() -> Self {
+        let listen_fds = variable("LISTEN_FDS", "LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDNAMES");
+
+        Self {
+            listen_fds,
+            notify_socket: env::var_os("NOTIFY_SOCKET").map(PathBuf::from),
+            watchdog_usec: variable("WATCHDOG_USEC", "WATCHDOG_PID"),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// helpers
+
+/// Returns the variable if it is set for this process, as systemd sets the
+/// pid to which the sockets and the watchdog are meant
+fn variable(name: &str, pid: &str) -> Option<String> {
+    let value = env::var(name).ok()?;
+
+    env::var(pid)
+        .is_ok_and(|pid| pid == process::id().to_string())
+        .then_some(value)
+}
+
+/// Returns the listening sockets passed by systemd socket activation, if any
+#[tracing::instrument(skip_all)]
+pub fn listeners(environment: &Environment) -> Result<Vec<Bound>, Error> {
+    let Some(fds) = &environment.listen_fds else {
+        return Ok(vec![]);
+    };
+
+    let count = fds
+        .parse::<RawFd>()
+        .map_err(|_| Error::Parse("LISTEN_FDS", fds.to_owned()))?;
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(activated)
+        .collect()
+}
+
+/// Take ownership of a listening socket passed by systemd
+fn activated(raw_fd: RawFd) -> Result<Bound, Error> {
+    let family = getsockname::<SockaddrStorage>(raw_fd)
+        .map_err(|err| Error::SocketAddress(raw_fd, err))?
+        .family();
+
+    // SAFETY: systemd passes the file descriptors from `LISTEN_FDS_START` to
+    // the process, which owns them from now on and takes them only once
+    let fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
+
+    // e.g. a datagram socket, or a stream socket that is not listening
+    let listening = getsockopt(&fd, sockopt::SockType)
+        .is_ok_and(|sock_type| sock_type == SockType::Stream)
+        && getsockopt(&fd, sockopt::AcceptConn).unwrap_or(false);
+    if !listening {
+        return Err(Error::NotListening(raw_fd));
+    }
+
+    match family {
+        Some(AddressFamily::Inet | AddressFamily::Inet6) => {
+            let listener = std::net::TcpListener::from(fd);
+            listener
+                .set_nonblocking(true)
+                .and_then(|_| TcpListener::from_std(listener))
+                .map(Bound::Tcp)
+        }
+        Some(AddressFamily::Unix) => {
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            listener
+                .set_nonblocking(true)
+                .and_then(|_| UnixListener::from_std(listener))
+                .map(Bound::Unix)
+        }
+        _ => return Err(Error::UnsupportedSocket(raw_fd)),
+    }
+    .map_err(|err| Error::Socket(raw_fd, err))
+}
+
+/// Notify systemd of the state of the service, e.g. `READY=1`, on the socket
+/// at `path`
+pub fn notify(path: &Path, state: &str) -> Result<(), Error> {
+    let socket = UnixDatagram::unbound().map_err(Error::Notify)?;
+    match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            let address = SocketAddr::from_abstract_name(name).map_err(Error::Notify)?;
+            socket.send_to_addr(state.as_bytes(), &address)
+        }
+        _ => socket.send_to(state.as_bytes(), path),
+    }
+    .map_err(Error::Notify)?;
+
+    debug!(state = state, "Notified systemd");
+    Ok(())
+}
+
+/// Notify systemd if it expects notifications, logging the failure to do so
+fn try_notify(environment: &Environment, state: &str) {
+    let Some(path) = &environment.notify_socket else {
+        return;
+    };
+
+    if let Err(err) = notify(path, state) {
+        warn!(
+            error = err.to_string(),
+            state = state,
+            "Could not notify systemd"
+        );
+    }
+}
+
+/// Returns the interval at which systemd expects the watchdog to be pinged,
+/// if it is enabled
+pub fn watchdog(environment: &Environment) -> Result<Option<Duration>, Error> {
+    environment
+        .watchdog_usec
+        .as_ref()
+        .map(|usec| {
+            usec.parse()
+                .map(Duration::from_micros)
+                .map_err(|_| Error::Parse("WATCHDOG_USEC", usec.to_owned()))
+        })
+        .transpose()
+}
+
+/// Notify systemd that the connector is ready once every Sōzu instance is
+/// connected, or after [`READY_TIMEOUT`] otherwise, then ping the watchdog, if
+/// enabled, as long as every instance answers in time
+#[tracing::instrument(skip_all)]
+pub async fn supervise(shared: Shared, environment: Environment) {
+    let begin = Instant::now();
+    while !shared
+        .load()
+        .instances
+        .iter()
+        .all(|instance| instance.connection.is_connected())
+    {
+        if begin.elapsed() >= READY_TIMEOUT {
+            warn!(
+                timeout = READY_TIMEOUT.as_secs(),
+                "Sōzu is not connected in time, notify systemd that the connector is ready anyway"
+            );
+            break;
+        }
+
+        time::sleep(READY_POLL_INTERVAL).await;
+    }
+
+    info!("Notify systemd that the connector is ready");
+    try_notify(&environment, "READY=1");
+
+    let interval = match watchdog(&environment) {
+        Ok(Some(interval)) => interval / 2,
+        Ok(None) => return,
+        Err(err) => {
+            warn!(error = err.to_string(), "Could not enable the watchdog");
+            return;
+        }
+    };
+
+    let mut ticker = time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        let state = shared.load();
+        let answers = future::join_all(state.instances.iter().map(|instance| {
+            sozu::list_workers(&instance.connection, instance.deadline(Some(interval)))
+        }))
+        .await;
+
+        match answers.into_iter().find_map(Result::err) {
+            None => try_notify(&environment, "WATCHDOG=1"),
+            Some(err) => warn!(
+                error = err.to_string(),
+                "Sōzu does not answer, do not ping the watchdog of systemd"
+            ),
+        }
+    }
+}
+
+/// Notify systemd that the connector is shutting down
+pub fn stopping(environment: &Environment) {
+    try_notify(environment, "STOPPING=1");
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::fd::IntoRawFd;
+
+    use super::*;
+
+    #[test]
+    fn notify_systemd() {
+        let path = env::temp_dir().join(format!(
+            "sozu-prometheus-connector-notify-{}.sock",
+            process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).expect("socket to be bound");
+
+        notify(&path, "READY=1").expect("notification to be sent");
+        let mut buf = [0; 64];
+        let read = socket.recv(&mut buf).expect("notification to be received");
+        assert_eq!(&buf[..read], b"READY=1");
+
+        std::fs::remove_file(&path).expect("socket to be removed");
+    }
+
+    #[tokio::test]
+    async fn take_listening_sockets_only() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("socket to be bound");
+        let address = listener.local_addr().expect("socket to have an address");
+        let stream = std::net::TcpStream::connect(address).expect("socket to be connected");
+        let datagram = std::net::UdpSocket::bind("127.0.0.1:0").expect("socket to be bound");
+
+        assert!(matches!(
+            activated(listener.into_raw_fd()),
+            Ok(Bound::Tcp(_))
+        ));
+        assert!(matches!(
+            activated(stream.into_raw_fd()),
+            Err(Error::NotListening(_))
+        ));
+        assert!(matches!(
+            activated(datagram.into_raw_fd()),
+            Err(Error::NotListening(_))
+        ));
+    }
+}
@@ -0,0 +1,874 @@
+//! # Collector module
+//!
+//! This module provides helpers to query the metrics of the Sōzu instances and
+//! a background task per instance that polls it on an interval and keeps the
+//! last snapshot, from which scrapes are served.
+
+use std::{
+    fmt::{self, Display},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures_util::future;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use sozu_command_lib::{
+    channel::ChannelError,
+    proto::command::{
+        self, request::RequestType, response_content::ContentType, AggregatedMetrics,
+        QueryMetricsOptions, ResponseContent,
+    },
+};
+use tokio::{
+    sync::OnceCell,
+    time::{self, MissedTickBehavior},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::svc::{
+    config::ConnectorConfiguration,
+    http::server::{self, Instance},
+    sozu::{self, Deadline},
+    telemetry::{
+        self,
+        inventory::Inventory,
+        prometheus::{convert_instances_to_prometheus, Format, GaugeFamilies},
+    },
+};
+
+// -----------------------------------------------------------------------------
+// Telemetry
+
+static ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "sozu_errors_total",
+        "Number of failures to retrieve the metrics of Sōzu",
+        &["instance", "category"]
+    )
+    .expect("'sozu_errors_total' to not be already registered")
+});
+
+// -----------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to query Sōzu on its command socket, {0}")]
+    Query(sozu::Error),
+    #[error("failed to query Sōzu on its command socket, got response status {0}")]
+    InvalidResponse(i32),
+    #[error("Sōzu metrics are stale, last collected {}s ago", .0.as_secs())]
+    Stale(Duration),
+    #[error("Sōzu metrics have not been collected yet")]
+    NotCollected,
+}
+
+impl Error {
+    pub fn category(&self) -> Category {
+        match self {
+            Self::Query(sozu::Error::Send(err)) => match err.as_ref() {
+                sozu_client::Error::GetConnection(bb8::RunError::TimedOut)
+                | sozu_client::Error::Send(ChannelError::TimeoutReached(_))
+                | sozu_client::Error::Receive(ChannelError::TimeoutReached(_)) => Category::Timeout,
+                sozu_client::Error::InvalidStatusCode(_) | sozu_client::Error::Failure(..) => {
+                    Category::InvalidResponse
+                }
+                _ => Category::Unreachable,
+            },
+            Self::Query(sozu::Error::Timeout(_)) => Category::Timeout,
+            Self::Query(sozu::Error::InvalidResponse(_)) => Category::InvalidResponse,
+            Self::Query(_) => Category::Unreachable,
+            Self::InvalidResponse(_) => Category::InvalidResponse,
+            Self::Stale(_) | Self::NotCollected => Category::Stale,
+        }
+    }
+}
+
+/// The kind of failure to retrieve the metrics of Sōzu
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Category {
+    /// The command socket could not be reached
+    Unreachable,
+    /// Sōzu answered with an unexpected response
+    InvalidResponse,
+    /// Sōzu did not answer in time
+    Timeout,
+    /// The metrics collected in background are too old, or missing
+    Stale,
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unreachable => write!(f, "unreachable"),
+            Self::InvalidResponse => write!(f, "invalid_response"),
+            Self::Timeout => write!(f, "timeout"),
+            Self::Stale => write!(f, "stale"),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Exposition
+
+/// The metrics of Sōzu instances serialized for a scrape, followed by the
+/// meta metrics describing their retrieval. The error is set if no instance
+/// could be retrieved.
+#[derive(Debug)]
+pub struct Exposition {
+    pub body: String,
+    pub error: Option<Arc<Error>>,
+}
+
+// -----------------------------------------------------------------------------
+// Flights
+
+/// A query to Sōzu, shared by the scrapes that wait for it. Each scrape
+/// converts the retrieved metrics to its own format.
+type Flight = Arc<OnceCell<Arc<Vec<Retrieval>>>>;
+
+/// What a flight answers: the instance queried, all of them if none, and the
+/// options of the query
+type Destination = (Option<String>, QueryMetricsOptions);
+
+/// The queries to Sōzu in flight, with their destination
+pub type Flights = Arc<Mutex<Vec<(Destination, Flight)>>>;
+
+// -----------------------------------------------------------------------------
+// Snapshot
+
+/// The metrics of a Sōzu instance, at the time they were collected
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub collected_at: SystemTime,
+    /// Time taken by the query to Sōzu
+    pub duration: Duration,
+    pub metrics: AggregatedMetrics,
+    pub inventory: Inventory,
+}
+
+impl Snapshot {
+    pub fn new(metrics: AggregatedMetrics, inventory: Inventory, duration: Duration) -> Self {
+        Self {
+            collected_at: SystemTime::now(),
+            duration,
+            metrics,
+            inventory,
+        }
+    }
+
+    /// Returns the time elapsed since the collection
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.collected_at)
+            .unwrap_or_default()
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Report
+
+/// The outcome of the last retrieval of the metrics of a Sōzu instance
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub up: bool,
+    /// Time taken to query Sōzu and to convert its metrics
+    pub duration: Duration,
+    /// Number of series exported for the instance
+    pub series: usize,
+    pub error: Option<String>,
+    pub retrieved_at: Option<SystemTime>,
+    /// Time of the last successful query to Sōzu
+    pub last_success: Option<SystemTime>,
+}
+
+/// The metrics of a Sōzu instance as retrieved for a scrape, from Sōzu or from
+/// its snapshot
+#[derive(Debug)]
+pub struct Retrieval {
+    instance: Instance,
+    result: Result<AggregatedMetrics, Arc<Error>>,
+    inventory: Inventory,
+    /// Time taken by the query to Sōzu
+    duration: Duration,
+    /// Time at which Sōzu was queried
+    queried_at: SystemTime,
+}
+
+// -----------------------------------------------------------------------------
+// helpers
+
+/// Query the metrics of a Sōzu instance on its command socket, until `deadline`
+#[tracing::instrument(skip_all, fields(instance = instance.name))]
+pub async fn query(
+    instance: &Instance,
+    opts: QueryMetricsOptions,
+    deadline: Deadline,
+) -> Result<AggregatedMetrics, Error> {
+    debug!("Querying Sōzu metrics");
+    let result = match instance
+        .connection
+        .send(RequestType::QueryMetrics(opts), deadline)
+        .await
+    {
+        Ok(command::Response {
+            content:
+                Some(ResponseContent {
+                    content_type: Some(ContentType::Metrics(aggregated_metrics)),
+                }),
+            ..
+        }) => Ok(aggregated_metrics),
+        Ok(response) => Err(Error::InvalidResponse(response.status)),
+        Err(err) => Err(Error::Query(err)),
+    };
+
+    result.inspect_err(|err| record(instance, err))
+}
+
+/// Query the metrics of the instance named `scope`, or of all instances, and
+/// convert them. Concurrent calls with the same scope and options share one
+/// in-flight query, whatever their format.
+///
+/// Each instance is given its query timeout to answer, or `timeout` if shorter.
+/// An instance that fails to answer is reported as down, the exposition is in
+/// error only if no instance answered.
+#[tracing::instrument(skip(state, opts))]
+pub async fn fetch(
+    state: &server::State,
+    scope: Option<&str>,
+    opts: QueryMetricsOptions,
+    format: Format,
+    timeout: Option<Duration>,
+) -> Exposition {
+    let reported = scope.is_none() && !filtered(&opts);
+    let destination = (scope.map(ToOwned::to_owned), opts);
+    let (flight, joined) = {
+        let mut flights = state
+            .flights
+            .lock()
+            .expect("flights lock to not be poisoned");
+        match flights.iter().find(|(other, _)| *other == destination) {
+            Some((_, flight)) => {
+                debug!("Joining the in-flight query to Sōzu");
+                (flight.to_owned(), true)
+            }
+            None => {
+                let flight = Flight::default();
+                flights.push((destination.to_owned(), flight.to_owned()));
+                (flight, false)
+            }
+        }
+    };
+
+    let landing = flight.get_or_init(|| async {
+        let (_, opts) = &destination;
+        let retrievals = future::join_all(state.instances(scope).map(|instance| async move {
+            let deadline = instance.deadline(timeout);
+            let queried_at = SystemTime::now();
+            let begin = Instant::now();
+            let result = query(instance, opts.to_owned(), deadline).await;
+            let duration = begin.elapsed();
+            if let Err(err) = &result {
+                error!(
+                    instance = instance.name,
+                    error = err.to_string(),
+                    "Could not query Sōzu on its command socket"
+                );
+            }
+
+            // the inventory is left out of filtered scrapes, and of a Sōzu that
+            // just failed to answer
+            let inventory = if filtered(opts) || result.is_err() {
+                Inventory::default()
+            } else {
+                instance.inventory()
+            };
+
+            Retrieval {
+                instance: instance.to_owned(),
+                result: result.map_err(Arc::new),
+                inventory,
+                duration,
+                queried_at,
+            }
+        }))
+        .await;
+
+        Arc::new(retrievals)
+    });
+
+    // the query was sent with the timeout of another scrape, which may be
+    // longer than the one of this scrape
+    let retrievals = match timeout.filter(|_| joined) {
+        Some(timeout) => match time::timeout(timeout, landing).await {
+            Ok(retrievals) => retrievals.to_owned(),
+            Err(_) => {
+                warn!("The in-flight query to Sōzu did not land within the timeout of the scrape");
+                return timed_out(state, scope, format, timeout, reported);
+            }
+        },
+        None => landing.await.to_owned(),
+    };
+
+    // the flight has landed, the next scrapes have to query Sōzu again
+    state
+        .flights
+        .lock()
+        .expect("flights lock to not be poisoned")
+        .retain(|(_, other)| !Arc::ptr_eq(other, &flight));
+
+    render(state, scope, &retrievals, format, reported)
+}
+
+/// Convert the snapshots of the instance named `scope`, or of all instances.
+/// A snapshot older than `max_staleness` is reported as down, the exposition
+/// is in error only if no instance has a fresh snapshot.
+#[tracing::instrument(skip(state))]
+pub fn from_snapshots(
+    state: &server::State,
+    scope: Option<&str>,
+    format: Format,
+    max_staleness: Duration,
+) -> Exposition {
+    let mut retrievals = Vec::new();
+    for instance in state.instances(scope) {
+        let snapshot = instance
+            .snapshot
+            .read()
+            .expect("snapshot lock to not be poisoned");
+
+        let (result, inventory, duration, queried_at) = match snapshot.as_ref() {
+            Some(snapshot) if snapshot.age() <= max_staleness => (
+                Ok(snapshot.metrics.to_owned()),
+                snapshot.inventory.to_owned(),
+                snapshot.duration,
+                snapshot.collected_at,
+            ),
+            Some(snapshot) => {
+                warn!(
+                    instance = instance.name,
+                    age = snapshot.age().as_secs(),
+                    "Sōzu metrics are stale"
+                );
+                (
+                    Err(Error::Stale(snapshot.age())),
+                    Inventory::default(),
+                    Duration::ZERO,
+                    SystemTime::now(),
+                )
+            }
+            None => {
+                warn!(
+                    instance = instance.name,
+                    "Sōzu metrics have not been collected yet"
+                );
+                (
+                    Err(Error::NotCollected),
+                    Inventory::default(),
+                    Duration::ZERO,
+                    SystemTime::now(),
+                )
+            }
+        };
+
+        if let Err(err) = &result {
+            record(instance, err);
+        }
+
+        retrievals.push(Retrieval {
+            instance: instance.to_owned(),
+            result: result.map_err(Arc::new),
+            inventory,
+            duration,
+            queried_at,
+        });
+    }
+
+    render(state, scope, &retrievals, format, scope.is_none())
+}
+
+/// Report the instance named `scope`, or all instances, as having not answered
+/// within `timeout`
+fn timed_out(
+    state: &server::State,
+    scope: Option<&str>,
+    format: Format,
+    timeout: Duration,
+    reported: bool,
+) -> Exposition {
+    let retrievals = state
+        .instances(scope)
+        .map(|instance| {
+            let err = Error::Query(sozu::Error::Timeout(timeout));
+            record(instance, &err);
+
+            Retrieval {
+                instance: instance.to_owned(),
+                result: Err(Arc::new(err)),
+                inventory: Inventory::default(),
+                duration: timeout,
+                queried_at: SystemTime::now(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    render(state, scope, &retrievals, format, reported)
+}
+
+/// Whether the options of a query filter the metrics of Sōzu
+pub fn filtered(opts: &QueryMetricsOptions) -> bool {
+    !opts.cluster_ids.is_empty()
+        || !opts.backend_ids.is_empty()
+        || !opts.metric_names.is_empty()
+        || opts.no_clusters
+}
+
+/// Remove the failures counted for an instance that is no longer scraped
+pub fn forget(instance: &str) {
+    telemetry::forget(&ERRORS, instance);
+}
+
+/// Count a failure to retrieve the metrics of an instance
+fn record(instance: &Instance, err: &Error) {
+    ERRORS
+        .with_label_values(&[instance.name.as_str(), &err.category().to_string()])
+        .inc();
+}
+
+/// Convert the retrieved metrics using the settings of the connector and
+/// append the meta metrics describing the retrieval. When all instances are
+/// exported together and configured as a list, the metrics are labelled with
+/// `instance`.
+///
+/// The report of each instance is only updated if `reported`, so that `/status`
+/// describes the scrapes of all instances without filters.
+fn render(
+    state: &server::State,
+    scope: Option<&str>,
+    retrievals: &[Retrieval],
+    format: Format,
+    reported: bool,
+) -> Exposition {
+    let begin = Instant::now();
+    let config = &state.config;
+    let labelled = scope.is_none() && config.sozu.is_list();
+
+    let mut outcomes = Vec::new();
+    let mut metrics = Vec::new();
+    let mut error = None;
+    for retrieval in retrievals {
+        let outcome = match &retrieval.result {
+            Ok(aggregated_metrics) => {
+                metrics.push((
+                    labelled.then(|| retrieval.instance.name.to_owned()),
+                    aggregated_metrics.to_owned(),
+                ));
+                Ok(retrieval.queried_at)
+            }
+            Err(err) => {
+                error = Some(err.to_owned());
+                Err(err.to_string())
+            }
+        };
+
+        outcomes.push((
+            &retrieval.instance,
+            &retrieval.inventory,
+            retrieval.duration,
+            outcome,
+        ));
+    }
+
+    let succeeded = !metrics.is_empty();
+    let (mut body, series) = convert_instances_to_prometheus(
+        metrics,
+        config.per_worker_metrics,
+        format,
+        &config.descriptions,
+        config.label_encoding,
+    );
+    let conversion = begin.elapsed();
+
+    // -------------------------------------------------------------------------
+    // Describe the retrieval and the inventory of each instance
+    let mut families = GaugeFamilies::default();
+    let mut series = series.into_iter();
+    for (instance, inventory, duration, outcome) in outcomes {
+        let mut report = instance
+            .report
+            .read()
+            .expect("report lock to not be poisoned")
+            .to_owned();
+
+        match outcome {
+            Ok(queried_at) => {
+                report.up = true;
+                report.duration = duration + conversion;
+                report.series = series.next().unwrap_or_default();
+                report.error = None;
+                report.last_success = Some(queried_at);
+            }
+            Err(message) => {
+                report.up = false;
+                report.duration = duration;
+                report.series = 0;
+                report.error = Some(message);
+            }
+        }
+        report.retrieved_at = Some(SystemTime::now());
+
+        let labels = if labelled {
+            vec![("instance", instance.name.as_str())]
+        } else {
+            vec![]
+        };
+
+        families
+            .family("sozu_up", "Whether the metrics of Sōzu could be retrieved")
+            .with_sample(&labels, if report.up { 1.0 } else { 0.0 });
+        families
+            .family(
+                "sozu_scrape_duration_seconds",
+                "Time taken to query Sōzu and to convert its metrics, in seconds",
+            )
+            .with_sample(&labels, report.duration.as_secs_f64());
+        families
+            .family(
+                "sozu_scrape_series_count",
+                "Number of series exported for Sōzu",
+            )
+            .with_sample(&labels, report.series as f64);
+
+        let last_successful_scrape = families.family(
+            "sozu_last_successful_scrape_timestamp_seconds",
+            "Time of the last successful query to Sōzu, as a unix timestamp in seconds",
+        );
+        if let Some(last_success) = report.last_success {
+            last_successful_scrape.with_sample(&labels, timestamp(last_success));
+        }
+
+        inventory.record(&mut families, &labels);
+
+        if reported {
+            *instance
+                .report
+                .write()
+                .expect("report lock to not be poisoned") = report;
+        }
+    }
+
+    body.push_str(&families.encode(format, config.label_encoding));
+
+    Exposition {
+        body,
+        error: error.filter(|_| !succeeded),
+    }
+}
+
+/// Returns the number of seconds elapsed since the unix epoch
+fn timestamp(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Poll a Sōzu instance every `interval` and store its metrics in a snapshot.
+/// On failure, the previous snapshot is kept and grows stale.
+#[tracing::instrument(skip_all, fields(instance = instance.name))]
+pub async fn collect(config: Arc<ConnectorConfiguration>, instance: Instance, interval: Duration) {
+    info!(
+        interval = interval.as_secs(),
+        "Begin to collect Sōzu metrics in background"
+    );
+
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        let opts = QueryMetricsOptions {
+            workers: config.per_worker_metrics,
+            ..Default::default()
+        };
+
+        let deadline = instance.deadline(None);
+        let begin = Instant::now();
+        match query(&instance, opts, deadline).await {
+            Ok(aggregated_metrics) => {
+                let duration = begin.elapsed();
+                let inventory = instance.inventory();
+                *instance
+                    .snapshot
+                    .write()
+                    .expect("snapshot lock to not be poisoned") =
+                    Some(Snapshot::new(aggregated_metrics, inventory, duration));
+            }
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "Could not collect Sōzu metrics, keeping the previous snapshot"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, path::PathBuf};
+
+    use sozu_command_lib::proto::command::{filtered_metrics::Inner, FilteredMetrics};
+
+    use super::*;
+    use crate::svc::{
+        http::server::test::{configuration, instance, state},
+        sozu::test::{connected, response, FakeSozu},
+    };
+
+    fn metrics(value: u64) -> AggregatedMetrics {
+        let mut proxying = BTreeMap::new();
+        proxying.insert(
+            "http.requests".to_owned(),
+            FilteredMetrics {
+                inner: Some(Inner::Gauge(value)),
+            },
+        );
+
+        AggregatedMetrics {
+            proxying,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn categorize_errors() {
+        let query = |err| Error::Query(sozu::Error::Send(Box::new(err)));
+
+        assert_eq!(
+            query(sozu_client::Error::GetConnection(bb8::RunError::TimedOut)).category(),
+            Category::Timeout
+        );
+        assert_eq!(
+            query(sozu_client::Error::Receive(ChannelError::TimeoutReached(
+                Duration::from_secs(1)
+            )))
+            .category(),
+            Category::Timeout
+        );
+        assert_eq!(
+            query(sozu_client::Error::Send(ChannelError::NoByteWritten)).category(),
+            Category::Unreachable
+        );
+        assert_eq!(
+            query(sozu_client::Error::InvalidStatusCode(3)).category(),
+            Category::InvalidResponse
+        );
+        assert_eq!(
+            Error::Query(sozu::Error::Disconnected).category(),
+            Category::Unreachable
+        );
+        assert_eq!(
+            Error::InvalidResponse(2).category(),
+            Category::InvalidResponse
+        );
+        assert_eq!(Error::NotCollected.category(), Category::Stale);
+    }
+
+    #[tokio::test]
+    async fn serve_from_snapshots() {
+        let config = configuration(
+            r#"
+[[sozu]]
+name = "fresh"
+configuration = "/etc/sozu/fresh.toml"
+
+[[sozu]]
+name = "stale"
+configuration = "/etc/sozu/stale.toml"
+
+[[sozu]]
+name = "missing"
+configuration = "/etc/sozu/missing.toml"
+"#,
+        );
+
+        let fresh = instance("fresh", PathBuf::from("/nonexistent/fresh.sock"));
+        let stale = instance("stale", PathBuf::from("/nonexistent/stale.sock"));
+        let missing = instance("missing", PathBuf::from("/nonexistent/missing.sock"));
+        *fresh.snapshot.write().unwrap() = Some(Snapshot::new(
+            metrics(12),
+            Inventory::default(),
+            Duration::from_millis(5),
+        ));
+        *stale.snapshot.write().unwrap() = Some(Snapshot {
+            collected_at: SystemTime::now() - Duration::from_secs(60),
+            ..Snapshot::new(metrics(3), Inventory::default(), Duration::ZERO)
+        });
+
+        let state = state(config, vec![fresh, stale, missing]);
+        let max_staleness = Duration::from_secs(30);
+
+        // a fresh snapshot is served, the others are reported as down
+        let exposition = from_snapshots(&state, None, Format::Text, max_staleness);
+        assert!(exposition.error.is_none());
+        assert!(exposition
+            .body
+            .contains(r#"http_requests_total{instance="fresh"} 12"#));
+        assert!(!exposition.body.contains(r#"instance="stale"} 3"#));
+        assert!(exposition.body.contains(r#"sozu_up{instance="fresh"} 1"#));
+        assert!(exposition.body.contains(r#"sozu_up{instance="stale"} 0"#));
+        assert!(exposition.body.contains(r#"sozu_up{instance="missing"} 0"#));
+
+        // an instance without a fresh snapshot is in error on its own
+        let exposition = from_snapshots(&state, Some("stale"), Format::Text, max_staleness);
+        assert!(
+            matches!(exposition.error.as_deref(), Some(Error::Stale(age)) if *age >= max_staleness)
+        );
+        assert_eq!(Category::Stale, exposition.error.unwrap().category());
+        assert!(exposition.body.contains("sozu_up{} 0"));
+
+        let exposition = from_snapshots(&state, Some("missing"), Format::Text, max_staleness);
+        assert!(matches!(
+            exposition.error.as_deref(),
+            Some(Error::NotCollected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn coalesce_scrapes_across_formats() {
+        let sozu = FakeSozu::start(
+            "coalesce",
+            Arc::new(|request| match request {
+                RequestType::QueryMetrics(_) => Some((
+                    Duration::from_millis(200),
+                    response(Some(ContentType::Metrics(metrics(7)))),
+                )),
+                _ => Some((Duration::ZERO, response(None))),
+            }),
+        );
+
+        let config = configuration(
+            r#"
+[[sozu]]
+name = "coalesce"
+configuration = "/etc/sozu/coalesce.toml"
+"#,
+        );
+
+        let instance = instance("coalesce", sozu.path.to_owned());
+        tokio::spawn(instance.connection.to_owned().maintain());
+        assert!(connected(&instance.connection).await);
+        let state = state(config, vec![instance]);
+
+        // concurrent scrapes in different formats share one query
+        let opts = QueryMetricsOptions::default();
+        let (text, openmetrics) = tokio::join!(
+            fetch(&state, None, opts.to_owned(), Format::Text, None),
+            fetch(&state, None, opts.to_owned(), Format::OpenMetrics, None),
+        );
+
+        assert_eq!(
+            1,
+            sozu.received(|request| matches!(request, RequestType::QueryMetrics(_)))
+        );
+        for exposition in [&text, &openmetrics] {
+            assert!(exposition.error.is_none());
+            assert!(exposition.body.contains(r#"instance="coalesce"} 7"#));
+        }
+        assert!(!text.body.contains("# UNIT"));
+        assert!(openmetrics.body.contains("# UNIT"));
+
+        // once the flight has landed, the next scrape queries Sōzu again
+        fetch(&state, None, opts, Format::Text, None).await;
+        assert_eq!(
+            2,
+            sozu.received(|request| matches!(request, RequestType::QueryMetrics(_)))
+        );
+    }
+
+    #[tokio::test]
+    async fn report_unfiltered_scrapes() {
+        let sozu = FakeSozu::start(
+            "report",
+            Arc::new(|request| match request {
+                RequestType::QueryMetrics(opts) if opts.cluster_ids.is_empty() => Some((
+                    Duration::ZERO,
+                    response(Some(ContentType::Metrics(metrics(7)))),
+                )),
+                _ => Some((
+                    Duration::ZERO,
+                    command::Response {
+                        status: command::ResponseStatus::Failure as i32,
+                        ..response(None)
+                    },
+                )),
+            }),
+        );
+
+        let config = configuration(
+            r#"
+[[sozu]]
+name = "report"
+configuration = "/etc/sozu/report.toml"
+"#,
+        );
+
+        let instance = instance("report", sozu.path.to_owned());
+        tokio::spawn(instance.connection.to_owned().maintain());
+        assert!(connected(&instance.connection).await);
+        let state = state(config, vec![instance.to_owned()]);
+
+        // the retrieval is described along with the metrics
+        let exposition = fetch(
+            &state,
+            None,
+            QueryMetricsOptions::default(),
+            Format::Text,
+            None,
+        )
+        .await;
+        assert!(exposition.error.is_none());
+        for family in [
+            r#"sozu_up{instance="report"} 1"#,
+            r#"sozu_scrape_duration_seconds{instance="report"} "#,
+            r#"sozu_scrape_series_count{instance="report"} "#,
+            r#"sozu_last_successful_scrape_timestamp_seconds{instance="report"} "#,
+        ] {
+            assert!(exposition.body.contains(family), "missing {family}");
+        }
+
+        let report = instance.report.read().unwrap().to_owned();
+        assert!(report.up);
+        assert!(report.series > 0);
+
+        // filtered scrapes leave the report untouched
+        let exposition = fetch(
+            &state,
+            None,
+            QueryMetricsOptions {
+                cluster_ids: vec!["cluster".to_owned()],
+                ..Default::default()
+            },
+            Format::Text,
+            None,
+        )
+        .await;
+        assert!(exposition.error.is_some());
+        assert!(exposition.body.contains(r#"sozu_up{instance="report"} 0"#));
+
+        fetch(
+            &state,
+            Some("report"),
+            QueryMetricsOptions::default(),
+            Format::Text,
+            None,
+        )
+        .await;
+
+        let unchanged = instance.report.read().unwrap().to_owned();
+        assert!(unchanged.up);
+        assert_eq!(report.series, unchanged.series);
+        assert_eq!(report.retrieved_at, unchanged.retrieved_at);
+    }
+}
@@ -0,0 +1,310 @@
+//! # Description module
+//!
+//! This module provides the help texts and units of the metrics exported by
+//! Sōzu 2.x, used to emit `# HELP` and `# UNIT` lines.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+// -----------------------------------------------------------------------------
+// Description
+
+/// The help text and the unit of a metric
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Description {
+    pub help: String,
+    /// Unit of the metric in its plural form (e.g. "seconds", "bytes"), only
+    /// announced in OpenMetrics if the metric name ends with it
+    #[serde(default)]
+    pub unit: Option<String>,
+}
+
+impl Description {
+    pub fn new<T>(help: T, unit: Option<&str>) -> Self
+    where
+        T: ToString,
+    {
+        Self {
+            help: help.to_string(),
+            unit: unit.map(ToString::to_string),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// helpers
+
+/// Retrieve the description of a metric from its Sōzu name, with dots replaced
+/// by underscores (e.g. `http_requests`). The configured overrides take
+/// precedence over the built-in catalogue.
+pub fn describe(name: &str, overrides: &BTreeMap<String, Description>) -> Option<Description> {
+    if let Some(description) = overrides.get(name) {
+        return Some(description.to_owned());
+    }
+
+    catalogue(name).map(|(help, unit)| Description::new(help, unit))
+}
+
+/// The built-in catalogue of the metrics of Sōzu 2.x
+fn catalogue(name: &str) -> Option<(&'static str, Option<&'static str>)> {
+    const BYTES: Option<&str> = Some("bytes");
+    const MILLISECONDS: Option<&str> = Some("milliseconds");
+    const PERCENT: Option<&str> = Some("percent");
+    const SECONDS: Option<&str> = Some("seconds");
+
+    let description = match name {
+        // accept queue
+        "accept_queue_backpressure" => (
+            "Number of times the accept queue applied backpressure",
+            None,
+        ),
+        "accept_queue_connections" => ("Number of connections waiting in the accept queue", None),
+        "accept_queue_saturated_seconds" => (
+            "Time spent with a saturated accept queue, in seconds",
+            SECONDS,
+        ),
+        "accept_queue_timeout" => (
+            "Number of connections that timed out in the accept queue",
+            None,
+        ),
+        "accept_queue_wait_time" => (
+            "Time spent by connections in the accept queue, in milliseconds",
+            MILLISECONDS,
+        ),
+
+        // access logs
+        "access_logs_count" => ("Number of access logs emitted", None),
+        "unsent-access-logs" => ("Number of access logs that could not be sent", None),
+
+        // backends
+        "bytes_in" => ("Bytes received from clients, in bytes", BYTES),
+        "bytes_out" => ("Bytes sent to clients, in bytes", BYTES),
+        "back_bytes_in" => ("Bytes received from backends, in bytes", BYTES),
+        "back_bytes_out" => ("Bytes sent to backends, in bytes", BYTES),
+        "backend_available" => ("Whether the backend is available", None),
+        "backend_connections" => ("Number of open connections to backends", None),
+        "backend_flow_control_paused" => (
+            "Number of times reading from a backend was paused by flow control",
+            None,
+        ),
+        "backend_pool_hit" => ("Number of backend connections reused from the pool", None),
+        "backend_pool_miss" => (
+            "Number of backend connections that could not be reused from the pool",
+            None,
+        ),
+        "backend_pool_size" => ("Number of idle backend connections in the pool", None),
+        "connections_per_backend" => ("Number of open connections per backend", None),
+        "backend_connection_time" => (
+            "Time to connect to a backend, in milliseconds",
+            MILLISECONDS,
+        ),
+        "backend_response_time" => (
+            "Time for a backend to answer a request, in milliseconds",
+            MILLISECONDS,
+        ),
+        "requests" => ("Number of requests forwarded to backends", None),
+        "backends_fail_open" => (
+            "Number of times all backends were unhealthy and traffic was sent anyway",
+            None,
+        ),
+
+        // buffers
+        "buffer_capacity" => ("Number of buffers the pool can hold", None),
+        "buffer_in_use" => ("Number of buffers in use", None),
+        "buffer_usage_percent" => ("Usage of the buffer pool, in percent", PERCENT),
+
+        // clients
+        "client_connections" => ("Number of open client connections", None),
+        "client_connections_max" => ("Maximum number of client connections", None),
+        "client_connections_percent" => (
+            "Usage of the client connections, in percent of the maximum",
+            PERCENT,
+        ),
+
+        // clusters
+        "cluster_available_backends" => ("Number of available backends of the cluster", None),
+        "cluster_available_recovered" => (
+            "Number of times the cluster recovered an available backend",
+            None,
+        ),
+        "cluster_no_available_backends" => {
+            ("Number of times the cluster had no available backend", None)
+        }
+        "cluster_total_backends" => ("Number of backends of the cluster", None),
+
+        // configuration
+        "configuration_backends" => ("Number of configured backends", None),
+        "configuration_clusters" => ("Number of configured clusters", None),
+        "configuration_frontends" => ("Number of configured frontends", None),
+
+        // event loop
+        "epoll_time" => (
+            "Time spent waiting for events, in milliseconds",
+            MILLISECONDS,
+        ),
+        "event_loop_time" => (
+            "Time spent in one iteration of the event loop, in milliseconds",
+            MILLISECONDS,
+        ),
+        "frontend_matching_time" => (
+            "Time spent matching a request against frontends, in milliseconds",
+            MILLISECONDS,
+        ),
+        "regex_matching_time" => (
+            "Time spent matching a request against regular expressions, in milliseconds",
+            MILLISECONDS,
+        ),
+        "request_time" => ("Time to serve a request, in milliseconds", MILLISECONDS),
+        "service_time" => (
+            "Time spent by Sōzu to process a request, in milliseconds",
+            MILLISECONDS,
+        ),
+
+        // health checks
+        "health_check_up" => ("Number of backends that health checks marked up", None),
+        "health_check_down" => ("Number of backends that health checks marked down", None),
+        "health_check_success" => ("Number of successful health checks", None),
+        "health_check_failure" => ("Number of failed health checks", None),
+
+        // http
+        "http_400_errors" => (
+            "Number of requests answered with a default 400 answer",
+            None,
+        ),
+        "http_404_errors" => (
+            "Number of requests answered with a default 404 answer",
+            None,
+        ),
+        "http_active_requests" => ("Number of HTTP requests being processed", None),
+        "http_alpn_h2" => (
+            "Number of connections that negotiated HTTP/2 through ALPN",
+            None,
+        ),
+        "http_alpn_http11" => (
+            "Number of connections that negotiated HTTP/1.1 through ALPN",
+            None,
+        ),
+        "http_backend_parse_errors" => (
+            "Number of responses from backends that could not be parsed",
+            None,
+        ),
+        "http_early_response_close" => (
+            "Number of backends that closed the connection before the end of the response",
+            None,
+        ),
+        "http_failed_backend_matching" => ("Number of requests that matched no backend", None),
+        "http_frontend_parse_errors" => (
+            "Number of requests from clients that could not be parsed",
+            None,
+        ),
+        "http_infinite_loop_error" => (
+            "Number of HTTP sessions stopped by the infinite loop guard",
+            None,
+        ),
+        "http_redirect_template_compile_error" => (
+            "Number of redirect templates that could not be compiled",
+            None,
+        ),
+        "http_requests" => ("Number of HTTP requests", None),
+        "http_status_1xx" => ("Number of responses with a 1xx status", None),
+        "http_status_2xx" => ("Number of responses with a 2xx status", None),
+        "http_status_3xx" => ("Number of responses with a 3xx status", None),
+        "http_status_4xx" => ("Number of responses with a 4xx status", None),
+        "http_status_5xx" => ("Number of responses with a 5xx status", None),
+        "http_status_other" => (
+            "Number of responses with a status outside of the standard classes",
+            None,
+        ),
+
+        // listeners
+        "listener_accepted_total" => ("Number of connections accepted by listeners", None),
+        "listener_connection_capped" => (
+            "Number of connections refused because of the connection limit",
+            None,
+        ),
+
+        // protocols
+        "protocol_http" => ("Number of sessions using HTTP", None),
+        "protocol_https" => ("Number of sessions using HTTPS", None),
+        "protocol_proxy_expect" => ("Number of sessions expecting a PROXY protocol header", None),
+        "protocol_proxy_relay" => ("Number of sessions relaying a PROXY protocol header", None),
+        "protocol_proxy_send" => ("Number of sessions sending a PROXY protocol header", None),
+        "protocol_tcp" => ("Number of sessions using TCP", None),
+        "protocol_tls_handshake" => ("Number of sessions in TLS handshake", None),
+        "protocol_ws" => ("Number of sessions using WebSocket", None),
+        "protocol_wss" => ("Number of sessions using secure WebSocket", None),
+        "pipe_errors" => ("Number of errors in the pipe protocol", None),
+        "proxy_protocol_errors" => ("Number of errors in the PROXY protocol", None),
+
+        // sessions and allocator
+        "sessions_evicted" => ("Number of sessions evicted", None),
+        "slab_capacity" => ("Number of sessions the slab can hold", None),
+        "slab_entries" => ("Number of sessions in the slab", None),
+        "slab_usage_percent" => ("Usage of the slab, in percent", PERCENT),
+        "slab_accept_threshold_percent" => (
+            "Slab usage above which new connections are refused, in percent",
+            PERCENT,
+        ),
+
+        // tcp
+        "tcp_requests" => ("Number of TCP sessions", None),
+        "tcp_read_error" => ("Number of errors while reading from a TCP socket", None),
+        "tcp_write_error" => ("Number of errors while writing to a TCP socket", None),
+        "tcp_sni_preread_routed" => (
+            "Number of TCP sessions routed from the SNI of the TLS ClientHello",
+            None,
+        ),
+        "tcp_sni_preread_active" => ("Number of TCP sessions reading the TLS ClientHello", None),
+        "tcp_sni_preread_duration" => (
+            "Time spent reading the TLS ClientHello, in milliseconds",
+            MILLISECONDS,
+        ),
+
+        // tls
+        "tls_cert_min_expires_at_seconds" => (
+            "Earliest expiration date of the served certificates, as a unix timestamp in seconds",
+            SECONDS,
+        ),
+        "tls_default_cert_used" => (
+            "Number of TLS handshakes served with the default certificate",
+            None,
+        ),
+        "tls_handshake_ms" => (
+            "Time to complete a TLS handshake, in milliseconds",
+            MILLISECONDS,
+        ),
+
+        // udp
+        "udp_datagrams_in" => (
+            "Number of datagrams forwarded from clients to backends",
+            None,
+        ),
+        "udp_datagrams_out" => (
+            "Number of datagrams returned from backends to clients",
+            None,
+        ),
+        "udp_bytes_in" => (
+            "Payload bytes forwarded from clients to backends, in bytes",
+            BYTES,
+        ),
+        "udp_bytes_out" => (
+            "Payload bytes returned from backends to clients, in bytes",
+            BYTES,
+        ),
+        "udp_active_flows" => ("Number of active UDP flows", None),
+        "udp_flows_created" => ("Number of UDP flows created", None),
+        "udp_flows_evicted" => ("Number of UDP flows torn down", None),
+        "udp_flows_shed" => ("Number of UDP flows refused at the flow limit", None),
+        "udp_datagrams_dropped" => ("Number of datagrams dropped", None),
+        "udp_flow_duration" => ("Lifetime of a UDP flow, in milliseconds", MILLISECONDS),
+
+        // misc
+        "websocket_active_requests" => ("Number of active WebSocket connections", None),
+        "panic" => ("Number of panics caught in workers", None),
+        "zombies" => ("Number of zombie sessions cleaned up", None),
+        _ => return None,
+    };
+
+    Some(description)
+}
@@ -0,0 +1,331 @@
+//! # Events module
+//!
+//! This module provides a long-lived subscription to the events that each Sōzu
+//! instance pushes on its command socket, from which the transitions of its
+//! backends are exported and which are forwarded to the clients of `/events`.
+
+use std::{
+    sync::{atomic::Ordering, LazyLock},
+    time::Duration,
+};
+
+use futures_util::{stream, Stream, StreamExt};
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use sozu_command_lib::{
+    channel::ChannelError,
+    proto::command::{
+        response_content::ContentType, Event, EventKind, ResponseContent, ResponseStatus,
+    },
+};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::{self, JoinError},
+    time,
+};
+use tracing::{error, info, warn};
+
+use crate::svc::{http::server::Instance, sozu, telemetry};
+
+// -----------------------------------------------------------------------------
+// Constants
+
+/// Time to wait before subscribing again to the events of Sōzu once the
+/// subscription is lost
+pub const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Time after which the reader of the events checks whether its instance has
+/// been retired by a reload of the configuration, when Sōzu pushes nothing
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of events kept for a client of `/events` that reads them slower than
+/// Sōzu pushes them, older events are dropped beyond
+pub const CAPACITY: usize = 1024;
+
+// -----------------------------------------------------------------------------
+// Telemetry
+
+static BACKEND_EVENTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "sozu_backend_events_total",
+        "Number of backend events pushed by Sōzu",
+        &["instance", "kind", "cluster_id", "backend_id"]
+    )
+    .expect("'sozu_backend_events_total' to not be already registered")
+});
+
+static BACKEND_UP: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "sozu_backend_up",
+        "Whether a backend of Sōzu is up according to its last event, 1 if up, 0 if down",
+        &["instance", "cluster_id", "backend_id"]
+    )
+    .expect("'sozu_backend_up' to not be already registered")
+});
+
+// -----------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to subscribe to the events of Sōzu, {0}")]
+    Subscribe(sozu::Error),
+    #[error("failed to read event from Sōzu, {0}")]
+    Receive(ChannelError),
+    #[error("Sōzu refused the subscription, {0}")]
+    Failure(String),
+    #[error("failed to execute blocking task, {0}")]
+    Join(JoinError),
+}
+
+// -----------------------------------------------------------------------------
+// helpers
+
+/// Remove the series of the backends of an instance that is no longer scraped
+pub fn forget(instance: &str) {
+    telemetry::forget(&BACKEND_EVENTS, instance);
+    telemetry::forget(&BACKEND_UP, instance);
+}
+
+/// Subscribe to the events of a Sōzu instance and record them, subscribing
+/// again whenever the subscription is lost, e.g. when Sōzu restarts, until the
+/// instance is retired
+#[tracing::instrument(skip_all, fields(instance = instance.name))]
+pub async fn listen(instance: Instance) {
+    while !instance.is_retired() {
+        if let Err(err) = receive(&instance).await {
+            error!(
+                error = err.to_string(),
+                "Lost the subscription to the events of Sōzu, subscribe again in {}s",
+                RESUBSCRIBE_DELAY.as_secs()
+            );
+        }
+
+        time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+/// Subscribe to the events of a Sōzu instance and record them until the
+/// subscription is lost or the instance is retired
+async fn receive(instance: &Instance) -> Result<(), Error> {
+    let mut channel = sozu::subscribe(&instance.properties)
+        .await
+        .map_err(Error::Subscribe)?;
+
+    info!("Subscribed to the events of Sōzu");
+
+    // The channel is blocking, so events are read on a dedicated thread
+    let name = instance.name.to_owned();
+    let events = instance.events.to_owned();
+    let retired = instance.retired.to_owned();
+    task::spawn_blocking(move || loop {
+        let response = match channel.read_message_blocking_timeout(Some(POLL_INTERVAL)) {
+            Ok(response) => response,
+            Err(ChannelError::TimeoutReached(_)) if retired.load(Ordering::Relaxed) => {
+                return Ok(());
+            }
+            Err(ChannelError::TimeoutReached(_)) => continue,
+            Err(err) => return Err(Error::Receive(err)),
+        };
+        if response.status == ResponseStatus::Failure as i32 {
+            return Err(Error::Failure(response.message));
+        }
+
+        if let Some(ResponseContent {
+            content_type: Some(ContentType::Event(event)),
+        }) = response.content
+        {
+            record(&name, &event);
+
+            // there may be no client to forward the event to
+            let _ = events.send(event);
+        }
+    })
+    .await
+    .map_err(Error::Join)?
+}
+
+/// Count a backend event and update the state of the backend, other events
+/// are ignored
+fn record(instance: &str, event: &Event) {
+    let Ok(kind) = EventKind::try_from(event.kind) else {
+        return;
+    };
+
+    if !matches!(
+        kind,
+        EventKind::BackendDown
+            | EventKind::BackendUp
+            | EventKind::NoAvailableBackends
+            | EventKind::RemovedBackendHasNoConnections
+    ) {
+        return;
+    }
+
+    let cluster_id = event.cluster_id.as_deref().unwrap_or_default();
+    let backend_id = event.backend_id.as_deref().unwrap_or_default();
+
+    BACKEND_EVENTS
+        .with_label_values(&[instance, kind.as_str_name(), cluster_id, backend_id])
+        .inc();
+
+    match kind {
+        EventKind::BackendUp => BACKEND_UP
+            .with_label_values(&[instance, cluster_id, backend_id])
+            .set(1),
+        EventKind::BackendDown => BACKEND_UP
+            .with_label_values(&[instance, cluster_id, backend_id])
+            .set(0),
+        // the backend has been removed and drained, forget it
+        EventKind::RemovedBackendHasNoConnections => {
+            let _ = BACKEND_UP.remove_label_values(&[instance, cluster_id, backend_id]);
+        }
+        _ => {}
+    }
+}
+
+/// Stream the events of the instances as they are pushed, along with the name
+/// of the instance they come from. Only the events of the given clusters are
+/// kept, if any.
+pub fn stream(
+    instances: &[Instance],
+    cluster_ids: Vec<String>,
+) -> impl Stream<Item = (String, Event)> + use<> {
+    let receivers = instances
+        .iter()
+        .map(|instance| (instance.name.to_owned(), instance.events.subscribe()))
+        .collect();
+
+    merge(receivers, cluster_ids)
+}
+
+/// Merge the events received from each instance, keeping only those of the
+/// given clusters, if any
+fn merge(
+    receivers: Vec<(String, broadcast::Receiver<Event>)>,
+    cluster_ids: Vec<String>,
+) -> impl Stream<Item = (String, Event)> {
+    let streams = receivers.into_iter().map(|(name, receiver)| {
+        Box::pin(stream::unfold(
+            (name, receiver),
+            |(name, mut receiver)| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(event) => return Some(((name.to_owned(), event), (name, receiver))),
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!(
+                                instance = name,
+                                skipped = skipped,
+                                "Client of the events of Sōzu is too slow, some events are dropped"
+                            );
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            },
+        ))
+    });
+
+    stream::select_all(streams).filter(move |(_, event)| {
+        let kept = cluster_ids.is_empty()
+            || event
+                .cluster_id
+                .as_ref()
+                .is_some_and(|cluster_id| cluster_ids.contains(cluster_id));
+
+        async move { kept }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(kind: EventKind, cluster_id: &str, backend_id: &str) -> Event {
+        Event {
+            kind: kind as i32,
+            cluster_id: Some(cluster_id.to_owned()),
+            backend_id: Some(backend_id.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn record_backend_events() {
+        let instance = "record-backend-events";
+
+        record(instance, &event(EventKind::BackendDown, "api", "api-0"));
+        record(instance, &event(EventKind::BackendUp, "api", "api-0"));
+        record(instance, &event(EventKind::BackendDown, "api", "api-1"));
+        record(instance, &event(EventKind::ClusterAdded, "api", ""));
+
+        assert_eq!(
+            BACKEND_UP
+                .with_label_values(&[instance, "api", "api-0"])
+                .get(),
+            1
+        );
+        assert_eq!(
+            BACKEND_UP
+                .with_label_values(&[instance, "api", "api-1"])
+                .get(),
+            0
+        );
+        assert_eq!(
+            BACKEND_EVENTS
+                .with_label_values(&[instance, "BACKEND_DOWN", "api", "api-0"])
+                .get(),
+            1
+        );
+        assert!(BACKEND_EVENTS
+            .remove_label_values(&[instance, "CLUSTER_ADDED", "api", ""])
+            .is_err());
+
+        record(
+            instance,
+            &event(EventKind::RemovedBackendHasNoConnections, "api", "api-1"),
+        );
+        assert!(BACKEND_UP
+            .remove_label_values(&[instance, "api", "api-1"])
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn merge_events() {
+        let (public, public_receiver) = broadcast::channel(CAPACITY);
+        let (private, private_receiver) = broadcast::channel(CAPACITY);
+
+        let mut events = Box::pin(merge(
+            vec![
+                ("public".to_owned(), public_receiver),
+                ("private".to_owned(), private_receiver),
+            ],
+            vec!["api".to_owned()],
+        ));
+
+        public
+            .send(event(EventKind::BackendDown, "web", "web-0"))
+            .expect("receiver to be alive");
+        public
+            .send(event(EventKind::BackendDown, "api", "api-0"))
+            .expect("receiver to be alive");
+        drop(public);
+        private
+            .send(event(EventKind::BackendUp, "api", "api-1"))
+            .expect("receiver to be alive");
+        drop(private);
+
+        let mut received = vec![];
+        while let Some((instance, event)) = events.next().await {
+            received.push((instance, event.backend_id.unwrap_or_default()));
+        }
+
+        received.sort();
+        assert_eq!(
+            received,
+            vec![
+                ("private".to_owned(), "api-1".to_owned()),
+                ("public".to_owned(), "api-0".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1020 @@
+//! # Inventory module
+//!
+//! This module provides what Sōzu reports about its state besides its metrics,
+//! e.g. its workers, the expiry of its certificates or its clusters, and
+//! exports it as gauges next to the metrics. The inventory is retrieved in
+//! background on an interval, so that scrapes do not pay for it.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::{stream, StreamExt};
+
+use sozu_command_lib::{
+    certificate::{parse_pem, parse_x509},
+    proto::command::{
+        CertificateAndKey, CertificatesByAddress, ClusterInformation, ListedFrontends,
+        ListenersList, LoadBalancingAlgorithms, RunState, WorkerInfo,
+    },
+};
+use tokio::time;
+use tracing::{debug, error, info, warn};
+
+use crate::svc::{
+    http::server::Instance,
+    sozu::{self, Deadline},
+    telemetry::prometheus::GaugeFamilies,
+};
+
+// -----------------------------------------------------------------------------
+// Constants
+
+/// Time between two retrievals of the inventory of a Sōzu instance
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of clusters whose configuration is queried at once
+pub const CLUSTER_QUERIES: usize = 8;
+
+// -----------------------------------------------------------------------------
+// Types
+
+/// Clusters of a Sōzu instance keyed by identifier, along with the hash of
+/// their configuration when they were retrieved. Only the clusters whose hash
+/// changed are queried again.
+pub type Clusters = Arc<Mutex<BTreeMap<String, (u64, Cluster)>>>;
+
+/// Expiration dates of the certificates served by a Sōzu instance keyed by
+/// fingerprint, none if it could not be read. A fingerprint identifies the
+/// content of a certificate, so only the unseen ones are read from the state.
+pub type Expirations = Arc<Mutex<BTreeMap<String, Option<i64>>>>;
+
+// -----------------------------------------------------------------------------
+// Inventory
+
+/// The state of a Sōzu instance, a part that could not be retrieved is left
+/// empty
+#[derive(Clone, Debug, Default)]
+pub struct Inventory {
+    pub workers: Option<Vec<WorkerInfo>>,
+    pub certificates: Option<Vec<Certificate>>,
+    pub clusters: Option<Vec<Cluster>>,
+    pub frontends: Option<Vec<Frontend>>,
+    pub listeners: Option<Vec<Listener>>,
+}
+
+/// A certificate served by Sōzu for a domain on a listener
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Certificate {
+    pub fingerprint: String,
+    pub listener: String,
+    pub domain: String,
+    /// Expiration date of the certificate, as a unix timestamp
+    pub not_after: i64,
+}
+
+/// A cluster of Sōzu
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Cluster {
+    pub cluster_id: String,
+    /// Sticky session and load balancing algorithm of the cluster, if it has
+    /// been configured and not only referenced by frontends or backends
+    pub configuration: Option<(bool, &'static str)>,
+    pub backends: usize,
+}
+
+impl Cluster {
+    pub fn new(cluster_id: String, information: ClusterInformation) -> Self {
+        let configuration = information.configuration.map(|cluster| {
+            (
+                cluster.sticky_session,
+                LoadBalancingAlgorithms::try_from(cluster.load_balancing)
+                    .map(|algorithm| algorithm.as_str_name())
+                    .unwrap_or("UNKNOWN"),
+            )
+        });
+
+        Self {
+            cluster_id,
+            configuration,
+            backends: information.backends.len(),
+        }
+    }
+}
+
+/// A frontend of Sōzu, routing the requests for a hostname and a path on a
+/// listener to a cluster
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Frontend {
+    /// Empty for frontends that do not route to a cluster, e.g. redirections
+    pub cluster_id: String,
+    /// Empty for tcp and udp frontends
+    pub hostname: String,
+    /// Empty for tcp and udp frontends
+    pub path: String,
+    pub listener: String,
+}
+
+/// A listener of Sōzu
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Listener {
+    pub address: String,
+    pub protocol: &'static str,
+    pub active: bool,
+}
+
+impl Inventory {
+    /// Retrieve the inventory of a Sōzu instance, until `deadline`
+    #[tracing::instrument(skip_all, fields(instance = instance.name))]
+    pub async fn retrieve(instance: &Instance, deadline: Deadline) -> Self {
+        let workers = sozu::list_workers(&instance.connection, deadline)
+            .await
+            .inspect_err(|err| {
+                error!(
+                    error = err.to_string(),
+                    "Could not list the workers of Sōzu"
+                )
+            })
+            .ok();
+
+        let certificates = retrieve_certificates(instance, deadline)
+            .await
+            .inspect_err(|err| {
+                error!(
+                    error = err.to_string(),
+                    "Could not retrieve the certificates of Sōzu"
+                )
+            })
+            .ok();
+
+        let clusters = retrieve_clusters(instance, deadline)
+            .await
+            .inspect_err(|err| {
+                error!(
+                    error = err.to_string(),
+                    "Could not retrieve the clusters of Sōzu"
+                )
+            })
+            .ok();
+
+        let frontends = sozu::frontends(&instance.connection, deadline)
+            .await
+            .inspect_err(|err| {
+                error!(
+                    error = err.to_string(),
+                    "Could not list the frontends of Sōzu"
+                )
+            })
+            .map(list_frontends)
+            .ok();
+
+        let listeners = sozu::listeners(&instance.connection, deadline)
+            .await
+            .inspect_err(|err| {
+                error!(
+                    error = err.to_string(),
+                    "Could not list the listeners of Sōzu"
+                )
+            })
+            .map(list_listeners)
+            .ok();
+
+        Self {
+            workers,
+            certificates,
+            clusters,
+            frontends,
+            listeners,
+        }
+    }
+
+    /// Add the gauges describing the inventory to the families, each sample
+    /// is labelled with `labels` first
+    pub fn record(&self, families: &mut GaugeFamilies, labels: &[(&str, &str)]) {
+        if let Some(workers) = &self.workers {
+            record_workers(families, labels, workers);
+        }
+
+        if let Some(certificates) = &self.certificates {
+            record_certificates(families, labels, certificates);
+        }
+
+        if let Some(clusters) = &self.clusters {
+            record_clusters(families, labels, clusters);
+        }
+
+        if let Some(frontends) = &self.frontends {
+            record_frontends(families, labels, frontends);
+        }
+
+        if let Some(listeners) = &self.listeners {
+            record_listeners(families, labels, listeners);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// helpers
+
+/// Retrieve the inventory of a Sōzu instance every [`REFRESH_INTERVAL`] while
+/// it is connected, and keep the last one, until the instance is retired
+#[tracing::instrument(skip_all, fields(instance = instance.name))]
+pub async fn refresh(instance: Instance) {
+    info!(
+        interval = REFRESH_INTERVAL.as_secs(),
+        "Begin to retrieve the inventory of Sōzu in background"
+    );
+
+    while !instance.is_retired() {
+        if !instance.connection.is_connected() {
+            time::sleep(sozu::MIN_BACKOFF).await;
+            continue;
+        }
+
+        debug!("Retrieve the inventory of Sōzu");
+        let inventory = Inventory::retrieve(&instance, instance.deadline(None)).await;
+        *instance
+            .inventory
+            .write()
+            .expect("inventory lock to not be poisoned") = inventory;
+
+        time::sleep(REFRESH_INTERVAL).await;
+    }
+}
+
+/// Returns the name of a run state as defined by the protocol, e.g. `RUNNING`
+fn run_state_name(run_state: i32) -> &'static str {
+    RunState::try_from(run_state)
+        .map(|run_state| run_state.as_str_name())
+        .unwrap_or("UNKNOWN")
+}
+
+/// Export one `sozu_worker_info` gauge per worker, and the number of workers in
+/// each run state
+fn record_workers(families: &mut GaugeFamilies, labels: &[(&str, &str)], workers: &[WorkerInfo]) {
+    let info = families.family(
+        "sozu_worker_info",
+        "Information about a worker of Sōzu, always 1",
+    );
+
+    for worker in workers {
+        let worker_id = worker.id.to_string();
+        let pid = worker.pid.to_string();
+        let mut worker_labels = labels.to_vec();
+        worker_labels.extend([
+            ("worker_id", worker_id.as_str()),
+            ("pid", pid.as_str()),
+            ("run_state", run_state_name(worker.run_state)),
+        ]);
+
+        info.with_sample(&worker_labels, 1.0);
+    }
+
+    let count = families.family("sozu_workers", "Number of workers of Sōzu by run state");
+    for run_state in [
+        RunState::Running,
+        RunState::Stopping,
+        RunState::Stopped,
+        RunState::NotAnswering,
+    ] {
+        let mut run_state_labels = labels.to_vec();
+        run_state_labels.push(("run_state", run_state.as_str_name()));
+
+        let workers = workers
+            .iter()
+            .filter(|worker| worker.run_state == run_state as i32)
+            .count();
+
+        count.with_sample(&run_state_labels, workers as f64);
+    }
+}
+
+/// Retrieve the certificates served by the workers of Sōzu, with their
+/// expiration date. The state of Sōzu holds the private key of every
+/// certificate, so it is only queried when a certificate is served for the
+/// first time.
+async fn retrieve_certificates(
+    instance: &Instance,
+    deadline: Deadline,
+) -> Result<Vec<Certificate>, sozu::Error> {
+    let served = sozu::served_certificates(&instance.connection, deadline).await?;
+    let unseen = {
+        let expirations = instance
+            .expirations
+            .lock()
+            .expect("expirations lock to not be poisoned");
+        fingerprints(&served).any(|fingerprint| !expirations.contains_key(fingerprint))
+    };
+
+    let certificates = if unseen {
+        sozu::certificates(&instance.connection, deadline).await?
+    } else {
+        BTreeMap::new()
+    };
+
+    let mut expirations = instance
+        .expirations
+        .lock()
+        .expect("expirations lock to not be poisoned");
+    read_expirations(&mut expirations, &served, &certificates);
+
+    Ok(join_certificates(served, &expirations))
+}
+
+/// Returns the fingerprints of the certificates served, with duplicates
+fn fingerprints(served: &[CertificatesByAddress]) -> impl Iterator<Item = &String> {
+    served.iter().flat_map(|by_address| {
+        by_address
+            .certificate_summaries
+            .iter()
+            .map(|summary| &summary.fingerprint)
+    })
+}
+
+/// Read the expiration date of the served certificates not seen yet from the
+/// certificates of the state of Sōzu, and forget the ones no longer served
+fn read_expirations(
+    expirations: &mut BTreeMap<String, Option<i64>>,
+    served: &[CertificatesByAddress],
+    certificates: &BTreeMap<String, CertificateAndKey>,
+) {
+    let mut kept = BTreeMap::new();
+    for fingerprint in fingerprints(served) {
+        if kept.contains_key(fingerprint) {
+            continue;
+        }
+
+        let not_after = match expirations.remove(fingerprint) {
+            Some(not_after) => not_after,
+            None => {
+                let not_after = certificates.get(fingerprint).and_then(expiration_date);
+                if not_after.is_none() {
+                    warn!(
+                        fingerprint = fingerprint,
+                        "Could not read the expiration date of the certificate"
+                    );
+                }
+
+                not_after
+            }
+        };
+
+        kept.insert(fingerprint.to_owned(), not_after);
+    }
+
+    *expirations = kept;
+}
+
+/// Join the certificates served on each listener with their expiration date,
+/// a certificate whose expiration date could not be read is left out
+fn join_certificates(
+    served: Vec<CertificatesByAddress>,
+    expirations: &BTreeMap<String, Option<i64>>,
+) -> Vec<Certificate> {
+    let mut joined = Vec::new();
+    for CertificatesByAddress {
+        address,
+        certificate_summaries,
+    } in served
+    {
+        let listener = SocketAddr::from(address).to_string();
+        for summary in certificate_summaries {
+            let Some(Some(not_after)) = expirations.get(&summary.fingerprint).copied() else {
+                continue;
+            };
+
+            let certificate = Certificate {
+                fingerprint: summary.fingerprint,
+                listener: listener.to_owned(),
+                domain: summary.domain,
+                not_after,
+            };
+
+            // each worker serves the same certificates
+            if !joined.contains(&certificate) {
+                joined.push(certificate);
+            }
+        }
+    }
+
+    joined
+}
+
+/// Read the expiration date of a certificate, as a unix timestamp
+fn expiration_date(certificate: &CertificateAndKey) -> Option<i64> {
+    let pem = parse_pem(certificate.certificate.as_bytes()).ok()?;
+    let x509 = parse_x509(&pem.contents).ok()?;
+
+    Some(x509.validity().not_after.timestamp())
+}
+
+/// Export the expiration date of each certificate, and the number of
+/// certificates served on each listener
+fn record_certificates(
+    families: &mut GaugeFamilies,
+    labels: &[(&str, &str)],
+    certificates: &[Certificate],
+) {
+    let not_after = families.family(
+        "sozu_certificate_not_after_seconds",
+        "Expiration date of a certificate served by Sōzu, as a unix timestamp in seconds",
+    );
+
+    let mut listeners: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for certificate in certificates {
+        let mut certificate_labels = labels.to_vec();
+        certificate_labels.extend([
+            ("fingerprint", certificate.fingerprint.as_str()),
+            ("listener", certificate.listener.as_str()),
+            ("domain", certificate.domain.as_str()),
+        ]);
+
+        not_after.with_sample(&certificate_labels, certificate.not_after as f64);
+
+        let fingerprints = listeners.entry(&certificate.listener).or_default();
+        if !fingerprints.contains(&certificate.fingerprint.as_str()) {
+            fingerprints.push(&certificate.fingerprint);
+        }
+    }
+
+    let count = families.family(
+        "sozu_certificates",
+        "Number of certificates served by Sōzu on a listener",
+    );
+    for (listener, fingerprints) in listeners {
+        let mut listener_labels = labels.to_vec();
+        listener_labels.push(("listener", listener));
+
+        count.with_sample(&listener_labels, fingerprints.len() as f64);
+    }
+}
+
+/// Retrieve the clusters of Sōzu, only the clusters that are not cached yet or
+/// whose configuration changed since their retrieval are queried, a few at once
+async fn retrieve_clusters(
+    instance: &Instance,
+    deadline: Deadline,
+) -> Result<Vec<Cluster>, sozu::Error> {
+    let hashes = sozu::cluster_hashes(&instance.connection, deadline).await?;
+
+    let outdated: Vec<(String, u64)> = {
+        let clusters = instance
+            .clusters
+            .lock()
+            .expect("clusters lock to not be poisoned");
+
+        hashes
+            .iter()
+            .filter(|(cluster_id, hash)| {
+                clusters
+                    .get(*cluster_id)
+                    .is_none_or(|(cached, _)| cached != *hash)
+            })
+            .map(|(cluster_id, hash)| (cluster_id.to_owned(), *hash))
+            .collect()
+    };
+
+    // a cluster that fails to be retrieved keeps its previous configuration, if
+    // any, and is queried again on the next retrieval
+    let retrieved: Vec<(u64, Cluster)> = stream::iter(outdated)
+        .map(|(cluster_id, hash)| async move {
+            match sozu::cluster(&instance.connection, &cluster_id, deadline).await {
+                Ok(information) => {
+                    information.map(|information| (hash, Cluster::new(cluster_id, information)))
+                }
+                Err(err) => {
+                    warn!(
+                        cluster_id = cluster_id,
+                        error = err.to_string(),
+                        "Could not retrieve the cluster of Sōzu"
+                    );
+                    None
+                }
+            }
+        })
+        .buffer_unordered(CLUSTER_QUERIES)
+        .filter_map(|retrieved| async move { retrieved })
+        .collect()
+        .await;
+
+    let mut clusters = instance
+        .clusters
+        .lock()
+        .expect("clusters lock to not be poisoned");
+
+    clusters.retain(|cluster_id, _| hashes.contains_key(cluster_id));
+    for (hash, cluster) in retrieved {
+        clusters.insert(cluster.cluster_id.to_owned(), (hash, cluster));
+    }
+
+    Ok(clusters
+        .values()
+        .map(|(_, cluster)| cluster.to_owned())
+        .collect())
+}
+
+/// Export one `sozu_cluster_info` gauge per configured cluster, and the number
+/// of backends of each cluster
+fn record_clusters(families: &mut GaugeFamilies, labels: &[(&str, &str)], clusters: &[Cluster]) {
+    let info = families.family(
+        "sozu_cluster_info",
+        "Information about a cluster of Sōzu, always 1",
+    );
+
+    for cluster in clusters {
+        let Some((sticky_session, load_balancing)) = cluster.configuration else {
+            continue;
+        };
+
+        let mut cluster_labels = labels.to_vec();
+        cluster_labels.extend([
+            ("cluster_id", cluster.cluster_id.as_str()),
+            (
+                "sticky_session",
+                if sticky_session { "true" } else { "false" },
+            ),
+            ("load_balancing", load_balancing),
+        ]);
+
+        info.with_sample(&cluster_labels, 1.0);
+    }
+
+    let backends = families.family("sozu_backends", "Number of backends of a cluster of Sōzu");
+    for cluster in clusters {
+        let mut cluster_labels = labels.to_vec();
+        cluster_labels.push(("cluster_id", cluster.cluster_id.as_str()));
+
+        backends.with_sample(&cluster_labels, cluster.backends as f64);
+    }
+}
+
+/// Flatten the frontends of Sōzu, frontends that only differ by what is not
+/// exported, e.g. their method, are listed once
+fn list_frontends(listed: ListedFrontends) -> Vec<Frontend> {
+    let http = listed
+        .http_frontends
+        .into_iter()
+        .chain(listed.https_frontends)
+        .map(|frontend| Frontend {
+            cluster_id: frontend.cluster_id.unwrap_or_default(),
+            hostname: frontend.hostname,
+            path: frontend.path.value,
+            listener: SocketAddr::from(frontend.address).to_string(),
+        });
+
+    let tcp = listed.tcp_frontends.into_iter().map(|frontend| Frontend {
+        cluster_id: frontend.cluster_id,
+        hostname: String::new(),
+        path: String::new(),
+        listener: SocketAddr::from(frontend.address).to_string(),
+    });
+
+    let udp = listed.udp_frontends.into_iter().map(|frontend| Frontend {
+        cluster_id: frontend.cluster_id,
+        hostname: String::new(),
+        path: String::new(),
+        listener: SocketAddr::from(frontend.address).to_string(),
+    });
+
+    let mut frontends = Vec::new();
+    for frontend in http.chain(tcp).chain(udp) {
+        if !frontends.contains(&frontend) {
+            frontends.push(frontend);
+        }
+    }
+
+    frontends
+}
+
+/// Export one `sozu_frontend_info` gauge per frontend
+fn record_frontends(families: &mut GaugeFamilies, labels: &[(&str, &str)], frontends: &[Frontend]) {
+    let info = families.family(
+        "sozu_frontend_info",
+        "Information about a frontend of Sōzu, always 1",
+    );
+
+    for frontend in frontends {
+        let mut frontend_labels = labels.to_vec();
+        frontend_labels.extend([
+            ("cluster_id", frontend.cluster_id.as_str()),
+            ("hostname", frontend.hostname.as_str()),
+            ("path", frontend.path.as_str()),
+            ("listener", frontend.listener.as_str()),
+        ]);
+
+        info.with_sample(&frontend_labels, 1.0);
+    }
+}
+
+/// Flatten the listeners of Sōzu
+fn list_listeners(listed: ListenersList) -> Vec<Listener> {
+    let http = listed
+        .http_listeners
+        .into_values()
+        .map(|listener| (listener.address, "http", listener.active));
+    let https = listed
+        .https_listeners
+        .into_values()
+        .map(|listener| (listener.address, "https", listener.active));
+    let tcp = listed
+        .tcp_listeners
+        .into_values()
+        .map(|listener| (listener.address, "tcp", listener.active));
+    let udp = listed
+        .udp_listeners
+        .into_values()
+        .map(|listener| (listener.address, "udp", listener.active));
+
+    http.chain(https)
+        .chain(tcp)
+        .chain(udp)
+        .map(|(address, protocol, active)| Listener {
+            address: SocketAddr::from(address).to_string(),
+            protocol,
+            active,
+        })
+        .collect()
+}
+
+/// Export one `sozu_listener_info` gauge per listener
+fn record_listeners(families: &mut GaugeFamilies, labels: &[(&str, &str)], listeners: &[Listener]) {
+    let info = families.family(
+        "sozu_listener_info",
+        "Information about a listener of Sōzu, always 1",
+    );
+
+    for listener in listeners {
+        let mut listener_labels = labels.to_vec();
+        listener_labels.extend([
+            ("listener", listener.address.as_str()),
+            ("protocol", listener.protocol),
+            ("active", if listener.active { "true" } else { "false" }),
+        ]);
+
+        info.with_sample(&listener_labels, 1.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sozu_command_lib::proto::command::{
+        request::RequestType, response_content::ContentType, AddBackend, CertificateSummary,
+        CertificatesWithFingerprints, Cluster as ClusterConfiguration, ClusterHashes,
+        ClusterInformations, HttpListenerConfig, HttpsListenerConfig, ListOfCertificatesByAddress,
+        PathRule, RequestHttpFrontend, RequestTcpFrontend, ResponseContent, SocketAddress,
+        WorkerResponses,
+    };
+
+    use crate::svc::{
+        http::server::test::instance,
+        sozu::test::{connected, FakeSozu},
+        telemetry::prometheus::{Format, LabelEncoding},
+    };
+
+    use super::*;
+
+    #[test]
+    fn encode_workers() {
+        let inventory = Inventory {
+            workers: Some(vec![
+                WorkerInfo {
+                    id: 0,
+                    pid: 1234,
+                    run_state: RunState::Running as i32,
+                },
+                WorkerInfo {
+                    id: 1,
+                    pid: 1235,
+                    run_state: RunState::Stopping as i32,
+                },
+            ]),
+            ..Default::default()
+        };
+
+        let mut families = GaugeFamilies::default();
+        inventory.record(&mut families, &[("instance", "public")]);
+
+        let expected = r#"# HELP sozu_worker_info Information about a worker of Sōzu, always 1
+# TYPE sozu_worker_info gauge
+sozu_worker_info{instance="public",worker_id="0",pid="1234",run_state="RUNNING"} 1
+sozu_worker_info{instance="public",worker_id="1",pid="1235",run_state="STOPPING"} 1
+# HELP sozu_workers Number of workers of Sōzu by run state
+# TYPE sozu_workers gauge
+sozu_workers{instance="public",run_state="RUNNING"} 1
+sozu_workers{instance="public",run_state="STOPPING"} 1
+sozu_workers{instance="public",run_state="STOPPED"} 0
+sozu_workers{instance="public",run_state="NOT_ANSWERING"} 0
+"#;
+
+        assert_eq!(
+            families.encode(Format::Text, LabelEncoding::Escape),
+            expected
+        );
+    }
+
+    /// A self-signed certificate for `example.com`, expiring on 2036-01-01
+    const CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----\nMIIBmjCCAT+gAwIBAgIUNicRIWrRQMfz8xAusGYAafIIfgwwCgYIKoZIzj0EAwIw\nFjEUMBIGA1UEAwwLZXhhbXBsZS5jb20wHhcNMjYwMTAxMDAwMDAwWhcNMzYwMTAx\nMDAwMDAwWjAWMRQwEgYDVQQDDAtleGFtcGxlLmNvbTBZMBMGByqGSM49AgEGCCqG\nSM49AwEHA0IABGjPu649wKoK288NeVrg2Asjat5iwPkAVJ1sJDFuArWR9IKSbr2/\nKQb1yvT0iV+9PWnTovT5RYHm3WVMfr5aLEKjazBpMB0GA1UdDgQWBBSlyCTG1F+5\nyRuyHo+bln5/4x4yAzAfBgNVHSMEGDAWgBSlyCTG1F+5yRuyHo+bln5/4x4yAzAP\nBgNVHRMBAf8EBTADAQH/MBYGA1UdEQQPMA2CC2V4YW1wbGUuY29tMAoGCCqGSM49\nBAMCA0kAMEYCIQDpJEu/eSzJeeaDBs7dSW56jHXATQsyVGiskwWa6NZ2PAIhANe8\nM/g39ON+uEs+SRgS300Ax0JhoBdJa9CrHQKpTwny\n-----END CERTIFICATE-----";
+
+    /// Returns the certificates served for `example.com` on a listener
+    fn summaries(fingerprints: &[&str]) -> CertificatesByAddress {
+        CertificatesByAddress {
+            address: SocketAddress::from("0.0.0.0:443".parse::<SocketAddr>().unwrap()),
+            certificate_summaries: fingerprints
+                .iter()
+                .map(|fingerprint| CertificateSummary {
+                    domain: "example.com".to_owned(),
+                    fingerprint: fingerprint.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn encode_certificates() {
+        let fingerprint = "d8a6811881711970".to_owned();
+        let mut certificates = BTreeMap::new();
+        certificates.insert(
+            fingerprint.to_owned(),
+            CertificateAndKey {
+                certificate: CERTIFICATE.to_owned(),
+                ..Default::default()
+            },
+        );
+        certificates.insert(
+            "unparsable".to_owned(),
+            CertificateAndKey {
+                certificate: "not a certificate".to_owned(),
+                ..Default::default()
+            },
+        );
+
+        // two workers serve the same certificates
+        let served = vec![
+            summaries(&[&fingerprint, "unparsable"]),
+            summaries(&[&fingerprint]),
+        ];
+        let mut expirations = BTreeMap::new();
+        read_expirations(&mut expirations, &served, &certificates);
+
+        let inventory = Inventory {
+            certificates: Some(join_certificates(served, &expirations)),
+            ..Default::default()
+        };
+
+        let mut families = GaugeFamilies::default();
+        inventory.record(&mut families, &[]);
+
+        let expected = r#"# HELP sozu_certificate_not_after_seconds Expiration date of a certificate served by Sōzu, as a unix timestamp in seconds
+# TYPE sozu_certificate_not_after_seconds gauge
+sozu_certificate_not_after_seconds{fingerprint="d8a6811881711970",listener="0.0.0.0:443",domain="example.com"} 2082758400
+# HELP sozu_certificates Number of certificates served by Sōzu on a listener
+# TYPE sozu_certificates gauge
+sozu_certificates{listener="0.0.0.0:443"} 1
+"#;
+
+        assert_eq!(
+            families.encode(Format::Text, LabelEncoding::Escape),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn query_state_for_unseen_certificates() {
+        let served: Arc<Mutex<Vec<&'static str>>> = Arc::new(Mutex::new(vec!["d8a6811881711970"]));
+        let sozu = FakeSozu::answering("unseen-certificates", {
+            let served = served.to_owned();
+            move |request| match request {
+                RequestType::QueryCertificatesFromWorkers(_) => {
+                    let served = served.lock().unwrap();
+                    let mut map = BTreeMap::new();
+                    map.insert(
+                        "0".to_owned(),
+                        ResponseContent {
+                            content_type: Some(ContentType::CertificatesByAddress(
+                                ListOfCertificatesByAddress {
+                                    certificates: vec![summaries(&served)],
+                                },
+                            )),
+                        },
+                    );
+                    Some(ContentType::WorkerResponses(WorkerResponses { map }))
+                }
+                RequestType::QueryCertificatesFromTheState(_) => {
+                    let mut certs = BTreeMap::new();
+                    certs.insert(
+                        "d8a6811881711970".to_owned(),
+                        CertificateAndKey {
+                            certificate: CERTIFICATE.to_owned(),
+                            ..Default::default()
+                        },
+                    );
+                    Some(ContentType::CertificatesWithFingerprints(
+                        CertificatesWithFingerprints { certs },
+                    ))
+                }
+                _ => None,
+            }
+        });
+
+        let instance = instance("unseen-certificates", sozu.path.to_owned());
+        tokio::spawn(instance.connection.to_owned().maintain());
+        assert!(connected(&instance.connection).await);
+
+        let deadline = || instance.deadline(None);
+        let queried = || {
+            sozu.received(|request| {
+                matches!(request, RequestType::QueryCertificatesFromTheState(_))
+            })
+        };
+
+        let certificates = retrieve_certificates(&instance, deadline()).await.unwrap();
+        assert_eq!(1, certificates.len());
+        assert_eq!(2082758400, certificates[0].not_after);
+        assert_eq!(1, queried());
+
+        // the expiration dates of the certificates seen are kept
+        let certificates = retrieve_certificates(&instance, deadline()).await.unwrap();
+        assert_eq!(1, certificates.len());
+        assert_eq!(1, queried());
+
+        // an unseen certificate is read once, even if it is not in the state
+        served.lock().unwrap().push("missing");
+        retrieve_certificates(&instance, deadline()).await.unwrap();
+        retrieve_certificates(&instance, deadline()).await.unwrap();
+        assert_eq!(2, queried());
+
+        // a certificate no longer served is forgotten
+        served
+            .lock()
+            .unwrap()
+            .retain(|fingerprint| *fingerprint == "missing");
+        assert!(retrieve_certificates(&instance, deadline())
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            vec!["missing"],
+            instance
+                .expirations
+                .lock()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_failing_clusters() {
+        let sozu = FakeSozu::answering("failing-clusters", |request| match request {
+            RequestType::QueryClustersHashes(_) => {
+                let map = [("api", 1), ("broken", 2), ("web", 3)]
+                    .into_iter()
+                    .map(|(cluster_id, hash)| (cluster_id.to_owned(), hash))
+                    .collect();
+                Some(ContentType::ClusterHashes(ClusterHashes { map }))
+            }
+            RequestType::QueryClusterById(cluster_id) if cluster_id != "broken" => {
+                Some(ContentType::Clusters(ClusterInformations {
+                    vec: vec![ClusterInformation {
+                        configuration: Some(ClusterConfiguration {
+                            cluster_id: cluster_id.to_owned(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }],
+                }))
+            }
+            _ => None,
+        });
+
+        let instance = instance("failing-clusters", sozu.path.to_owned());
+        tokio::spawn(instance.connection.to_owned().maintain());
+        assert!(connected(&instance.connection).await);
+
+        let queried = |id: &str| {
+            sozu.received(
+                |request| matches!(request, RequestType::QueryClusterById(cluster_id) if cluster_id == id),
+            )
+        };
+
+        // a cluster that fails to be retrieved does not hide the others
+        let clusters = retrieve_clusters(&instance, instance.deadline(None))
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["api", "web"],
+            clusters
+                .iter()
+                .map(|cluster| cluster.cluster_id.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        // and is the only one queried again
+        retrieve_clusters(&instance, instance.deadline(None))
+            .await
+            .unwrap();
+        assert_eq!(1, queried("api"));
+        assert_eq!(1, queried("web"));
+        assert_eq!(2, queried("broken"));
+    }
+
+    #[test]
+    fn encode_topology() {
+        let address = |address: &str| SocketAddress::from(address.parse::<SocketAddr>().unwrap());
+
+        let clusters = vec![
+            Cluster::new(
+                "api".to_owned(),
+                ClusterInformation {
+                    configuration: Some(ClusterConfiguration {
+                        cluster_id: "api".to_owned(),
+                        sticky_session: true,
+                        load_balancing: LoadBalancingAlgorithms::LeastLoaded as i32,
+                        ..Default::default()
+                    }),
+                    backends: vec![AddBackend::default(), AddBackend::default()],
+                    ..Default::default()
+                },
+            ),
+            Cluster::new("orphan".to_owned(), ClusterInformation::default()),
+        ];
+
+        let frontend = RequestHttpFrontend {
+            cluster_id: Some("api".to_owned()),
+            address: address("0.0.0.0:443"),
+            hostname: "example.com".to_owned(),
+            path: PathRule::prefix("/api".to_owned()),
+            ..Default::default()
+        };
+
+        // frontends that only differ by their method are listed once
+        let frontends = list_frontends(ListedFrontends {
+            https_frontends: vec![
+                frontend.to_owned(),
+                RequestHttpFrontend {
+                    method: Some("GET".to_owned()),
+                    ..frontend
+                },
+            ],
+            tcp_frontends: vec![RequestTcpFrontend {
+                cluster_id: "orphan".to_owned(),
+                address: address("0.0.0.0:5432"),
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+
+        let mut listeners = ListenersList::default();
+        listeners.http_listeners.insert(
+            "0.0.0.0:80".to_owned(),
+            HttpListenerConfig {
+                address: address("0.0.0.0:80"),
+                active: false,
+                ..Default::default()
+            },
+        );
+        listeners.https_listeners.insert(
+            "0.0.0.0:443".to_owned(),
+            HttpsListenerConfig {
+                address: address("0.0.0.0:443"),
+                active: true,
+                ..Default::default()
+            },
+        );
+
+        let inventory = Inventory {
+            clusters: Some(clusters),
+            frontends: Some(frontends),
+            listeners: Some(list_listeners(listeners)),
+            ..Default::default()
+        };
+
+        let mut families = GaugeFamilies::default();
+        inventory.record(&mut families, &[]);
+
+        let expected = r#"# HELP sozu_cluster_info Information about a cluster of Sōzu, always 1
+# TYPE sozu_cluster_info gauge
+sozu_cluster_info{cluster_id="api",sticky_session="true",load_balancing="LEAST_LOADED"} 1
+# HELP sozu_backends Number of backends of a cluster of Sōzu
+# TYPE sozu_backends gauge
+sozu_backends{cluster_id="api"} 2
+sozu_backends{cluster_id="orphan"} 0
+# HELP sozu_frontend_info Information about a frontend of Sōzu, always 1
+# TYPE sozu_frontend_info gauge
+sozu_frontend_info{cluster_id="api",hostname="example.com",path="/api",listener="0.0.0.0:443"} 1
+sozu_frontend_info{cluster_id="orphan",hostname="",path="",listener="0.0.0.0:5432"} 1
+# HELP sozu_listener_info Information about a listener of Sōzu, always 1
+# TYPE sozu_listener_info gauge
+sozu_listener_info{listener="0.0.0.0:80",protocol="http",active="false"} 1
+sozu_listener_info{listener="0.0.0.0:443",protocol="https",active="true"} 1
+"#;
+
+        assert_eq!(
+            families.encode(Format::Text, LabelEncoding::Escape),
+            expected
+        );
+    }
+}
@@ -3,4 +3,34 @@
 //! This module provides an helper to convert Sōzu internal telemetry into
 //! prometheus ones.
 
+use std::collections::HashMap;
+
+use ::prometheus::core::{Collector, MetricVec, MetricVecBuilder};
+
+pub mod collector;
+pub mod description;
+pub mod events;
+pub mod inventory;
+pub mod openmetrics;
 pub mod prometheus;
+
+// -----------------------------------------------------------------------------
+// helpers
+
+/// Remove the series of `vec` labelled with the given instance, whatever their
+/// other labels
+pub fn forget<T: MetricVecBuilder>(vec: &MetricVec<T>, instance: &str) {
+    for family in vec.collect() {
+        for metric in family.get_metric() {
+            let labels: HashMap<&str, &str> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.name(), label.value()))
+                .collect();
+
+            if labels.get("instance") == Some(&instance) {
+                let _ = vec.remove(&labels);
+            }
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! # OpenMetrics module
+//!
+//! This module provides an [`Encoder`] that serializes the connector's own
+//! registry into the [OpenMetrics 1.0] exposition format.
+//!
+//! [OpenMetrics 1.0]: https://github.com/prometheus/OpenMetrics/blob/main/specification/OpenMetrics.md
+
+use std::io::Write;
+
+use prometheus::{
+    proto::{LabelPair, Metric, MetricFamily, MetricType},
+    Encoder,
+};
+
+// -----------------------------------------------------------------------------
+// Constants
+
+/// The content type of the OpenMetrics exposition format.
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// The terminator that must end every OpenMetrics exposition.
+pub const EOF: &str = "# EOF\n";
+
+// -----------------------------------------------------------------------------
+// OpenMetricsEncoder
+
+/// Convert [`MetricFamily`] into the OpenMetrics text format.
+///
+/// The [`EOF`] terminator is not written, so that the output could be
+/// concatenated with the Sōzu families before terminating the exposition.
+#[derive(Default, Debug)]
+pub struct OpenMetricsEncoder;
+
+impl OpenMetricsEncoder {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(
+        &self,
+        metric_families: &[MetricFamily],
+        writer: &mut W,
+    ) -> prometheus::Result<()> {
+        for mf in metric_families {
+            let metric_type = mf.get_field_type();
+            let name = match metric_type {
+                MetricType::COUNTER => mf.name().strip_suffix("_total").unwrap_or(mf.name()),
+                _ => mf.name(),
+            };
+
+            let kind = match metric_type {
+                MetricType::COUNTER => "counter",
+                MetricType::GAUGE => "gauge",
+                MetricType::HISTOGRAM => "histogram",
+                MetricType::SUMMARY => "summary",
+                MetricType::UNTYPED => "unknown",
+            };
+
+            writeln!(writer, "# TYPE {name} {kind}")?;
+            if !mf.help().is_empty() {
+                writeln!(writer, "# HELP {name} {}", escape(mf.help()))?;
+            }
+
+            for m in mf.get_metric() {
+                match metric_type {
+                    MetricType::COUNTER => {
+                        write_sample(writer, name, "_total", m, None, m.get_counter().value())?;
+                    }
+                    MetricType::GAUGE => {
+                        write_sample(writer, name, "", m, None, m.get_gauge().value())?;
+                    }
+                    MetricType::UNTYPED => {
+                        write_sample(writer, name, "", m, None, m.untyped.value())?;
+                    }
+                    MetricType::HISTOGRAM => {
+                        let h = m.get_histogram();
+
+                        let mut inf_seen = false;
+                        for b in h.get_bucket() {
+                            let upper_bound = b.upper_bound();
+                            inf_seen |= upper_bound.is_infinite() && upper_bound.is_sign_positive();
+                            write_sample(
+                                writer,
+                                name,
+                                "_bucket",
+                                m,
+                                Some(("le", &format_float(upper_bound))),
+                                b.cumulative_count() as f64,
+                            )?;
+                        }
+
+                        if !inf_seen {
+                            write_sample(
+                                writer,
+                                name,
+                                "_bucket",
+                                m,
+                                Some(("le", "+Inf")),
+                                h.get_sample_count() as f64,
+                            )?;
+                        }
+
+                        write_sample(writer, name, "_sum", m, None, h.get_sample_sum())?;
+                        write_sample(writer, name, "_count", m, None, h.get_sample_count() as f64)?;
+                    }
+                    MetricType::SUMMARY => {
+                        let s = m.get_summary();
+
+                        for q in s.get_quantile() {
+                            write_sample(
+                                writer,
+                                name,
+                                "",
+                                m,
+                                Some(("quantile", &format_float(q.quantile()))),
+                                q.value(),
+                            )?;
+                        }
+
+                        write_sample(writer, name, "_sum", m, None, s.sample_sum())?;
+                        write_sample(writer, name, "_count", m, None, s.sample_count() as f64)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+// -----------------------------------------------------------------------------
+// helpers
+
+/// Write one sample line, labels are omitted if there is none
+fn write_sample<W: Write>(
+    writer: &mut W,
+    name: &str,
+    suffix: &str,
+    metric: &Metric,
+    additional_label: Option<(&str, &str)>,
+    value: f64,
+) -> prometheus::Result<()> {
+    let labels = metric
+        .get_label()
+        .iter()
+        .map(|pair: &LabelPair| (pair.name(), pair.value()))
+        .chain(additional_label)
+        .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    if labels.is_empty() {
+        writeln!(writer, "{name}{suffix} {}", format_float(value))?;
+    } else {
+        writeln!(writer, "{name}{suffix}{{{labels}}} {}", format_float(value))?;
+    }
+
+    Ok(())
+}
+
+/// Escape backslashes, double quotes and line feeds
+pub fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Format a float the way OpenMetrics expects it, infinities are written
+/// `+Inf` and `-Inf`
+pub fn format_float(value: f64) -> String {
+    if value.is_infinite() && value.is_sign_positive() {
+        "+Inf".to_string()
+    } else if value.is_infinite() {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
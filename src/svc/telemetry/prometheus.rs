@@ -1,16 +1,208 @@
-use std::{fmt::Display, iter};
+//! # Prometheus module
+//!
+//! This module converts Sōzu's aggregated metrics into the prometheus text
+//! format or into OpenMetrics.
 
+use std::{collections::BTreeMap, fmt::Display};
+
+use serde::{Deserialize, Serialize};
 use sozu_command_lib::proto::command::{
     filtered_metrics::Inner, AggregatedMetrics, BackendMetrics, FilteredMetrics, WorkerMetrics,
 };
 use tracing::debug;
 use urlencoding::encode;
 
+use crate::svc::telemetry::{
+    description::{describe, Description},
+    openmetrics::{self, OPENMETRICS_FORMAT},
+};
+
+// -----------------------------------------------------------------------------
+// Format
+
+/// Exposition format in which the metrics are serialized
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Format {
+    /// The legacy prometheus text format, version 0.0.4
+    #[default]
+    Text,
+    /// The OpenMetrics 1.0 text format
+    OpenMetrics,
+}
+
+impl Format {
+    /// Select the format from the `Accept` header of a scrape, OpenMetrics is
+    /// chosen only when it is explicitly accepted.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let accepted = accept.unwrap_or_default().split(',').any(|media_range| {
+            let mut params = media_range.split(';').map(str::trim);
+
+            params.next() == Some("application/openmetrics-text")
+                && params
+                    .filter_map(|param| param.strip_prefix("q="))
+                    .all(|q| q.parse::<f32>().is_ok_and(|q| q > 0.0))
+        });
+
+        if accepted {
+            Self::OpenMetrics
+        } else {
+            Self::Text
+        }
+    }
+
+    /// Returns the content type to answer with
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Text => mime::TEXT_PLAIN_UTF_8.as_ref(),
+            Self::OpenMetrics => OPENMETRICS_FORMAT,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// LabelEncoding
+
+/// How label values are written
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum LabelEncoding {
+    /// Escape backslashes, double quotes and line feeds as required by the
+    /// exposition formats, the value is otherwise preserved
+    Escape,
+    /// Percent-encode the value, as done by earlier releases
+    #[default]
+    Url,
+}
+
+impl LabelEncoding {
+    /// Write a label value with this encoding
+    pub fn encode(&self, label_value: &str) -> String {
+        match self {
+            Self::Escape => openmetrics::escape(label_value),
+            Self::Url => encode(label_value).into_owned(),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// GaugeFamily
+
+/// A family of gauges produced by the connector itself about the Sōzu
+/// instances, e.g. whether they could be scraped
+#[derive(Clone, Debug)]
+pub struct GaugeFamily {
+    name: String,
+    help: String,
+    samples: Vec<(Vec<(String, String)>, f64)>,
+}
+
+impl GaugeFamily {
+    pub fn new(name: &str, help: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            help: help.to_owned(),
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn with_sample(&mut self, labels: &[(&str, &str)], value: f64) {
+        self.samples.push((
+            labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            value,
+        ));
+    }
+
+    /// Serialize the family, nothing is written if it has no sample
+    pub fn encode(&self, format: Format, label_encoding: LabelEncoding) -> String {
+        if self.samples.is_empty() {
+            return String::new();
+        }
+
+        let name = &self.name;
+        let help = match format {
+            Format::Text => self.help.replace('\\', r"\\").replace('\n', r"\n"),
+            Format::OpenMetrics => openmetrics::escape(&self.help),
+        };
+
+        let help_line = format!("# HELP {name} {help}\n");
+        let mut lines = String::new();
+        match format {
+            Format::Text => {
+                lines.push_str(&help_line);
+                lines.push_str(&format!("# TYPE {name} gauge\n"));
+            }
+            Format::OpenMetrics => {
+                lines.push_str(&format!("# TYPE {name} gauge\n"));
+                if let Some(unit) = UNITS
+                    .iter()
+                    .find(|unit| name.ends_with(&format!("_{unit}")))
+                {
+                    lines.push_str(&format!("# UNIT {name} {unit}\n"));
+                }
+                lines.push_str(&help_line);
+            }
+        }
+
+        for (labels, value) in &self.samples {
+            let labels = labels
+                .iter()
+                .map(|(name, value)| format!("{name}=\"{}\"", label_encoding.encode(value)))
+                .collect::<Vec<_>>()
+                .join(",");
+
+            if labels.is_empty() && format == Format::OpenMetrics {
+                lines.push_str(&format!("{name} {}\n", openmetrics::format_float(*value)));
+            } else {
+                lines.push_str(&format!(
+                    "{name}{{{labels}}} {}\n",
+                    openmetrics::format_float(*value)
+                ));
+            }
+        }
+
+        lines
+    }
+}
+
+/// Gauge families produced by the connector, in the order of their creation
+#[derive(Clone, Debug, Default)]
+pub struct GaugeFamilies(Vec<GaugeFamily>);
+
+impl GaugeFamilies {
+    /// Returns the family with the given name, created if needed
+    pub fn family(&mut self, name: &str, help: &str) -> &mut GaugeFamily {
+        let index = match self.0.iter().position(|family| family.name == name) {
+            Some(index) => index,
+            None => {
+                self.0.push(GaugeFamily::new(name, help));
+                self.0.len() - 1
+            }
+        };
+
+        &mut self.0[index]
+    }
+
+    /// Serialize the families, those without sample are left out
+    pub fn encode(&self, format: Format, label_encoding: LabelEncoding) -> String {
+        self.0
+            .iter()
+            .map(|family| family.encode(format, label_encoding))
+            .collect()
+    }
+}
+
+// -----------------------------------------------------------------------------
+// LabeledMetric
+
 #[derive(PartialEq)]
 enum MetricType {
     Counter,
     Gauge,
     Histogram,
+    Summary,
     Unsupported,
 }
 
@@ -20,42 +212,166 @@ impl Display for MetricType {
             MetricType::Counter => write!(f, "counter"),
             MetricType::Gauge => write!(f, "gauge"),
             MetricType::Histogram => write!(f, "histogram"),
+            MetricType::Summary => write!(f, "summary"),
             MetricType::Unsupported => write!(f, "unsupported"), // should never happen
         }
     }
 }
 
+/// Units that could be announced in OpenMetrics, when the metric name ends
+/// with one of them
+const UNITS: [&str; 5] = ["seconds", "milliseconds", "bytes", "ratio", "percent"];
+
+/// Scope at which Sōzu measures a metric, which the exported name carries so
+/// that the families of the same Sōzu metric at different scopes stay distinct
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum Scope {
+    /// the main process
+    Main,
+    /// the proxy, aggregated over the workers
+    Proxying,
+    /// a cluster or one of its backends
+    #[default]
+    Cluster,
+    /// the proxy, for one worker
+    Worker,
+}
+
+impl Scope {
+    /// Suffix of the name of the family. The proxying counters rely on the
+    /// `_total` suffix of counter samples, so the per-cluster ones need their
+    /// own suffix to not share the sample name of the proxying ones.
+    fn suffix(self, counter: bool) -> &'static str {
+        match (self, counter) {
+            (Self::Main, _) => "_main",
+            (Self::Proxying, true) => "",
+            (Self::Proxying, false) => "_total",
+            (Self::Cluster, true) => "_cluster",
+            (Self::Cluster, false) => "",
+            (Self::Worker, _) => "_worker",
+        }
+    }
+}
+
 /// convertible to prometheus metric in this form:
 /// metric_name{label="something",second_label="something-else"} value
 struct LabeledMetric {
-    metric_name: String,
+    /// name of the metric in Sōzu, used to look up its description
+    sozu_name: String,
+    scope: Scope,
     labels: Vec<(String, String)>,
     value: FilteredMetrics,
     metric_type: MetricType,
+    /// index of the instance the metric comes from
+    source: usize,
 }
 
 impl LabeledMetric {
     fn with_name(&mut self, name: &str) {
-        self.metric_name = name.to_owned();
+        self.sozu_name = name.to_owned();
+    }
+
+    /// distinguish the family from the one of the same Sōzu metric at another scope
+    fn with_scope(&mut self, scope: Scope) {
+        self.scope = scope;
     }
 
-    fn with_label(&mut self, label_name: &str, label_value: &str) {
-        let label_value = encode(label_value);
+    fn with_label(&mut self, label_name: &str, label_value: &str, encoding: LabelEncoding) {
         self.labels
-            .push((label_name.to_owned(), label_value.into()));
+            .push((label_name.to_owned(), encoding.encode(label_value)));
     }
 
     /// remove dots from the name, replace with underscores
     fn printable_name(&self) -> String {
-        self.metric_name.replace('.', "_")
+        self.sozu_name.replace('.', "_")
+    }
+
+    /// Name of the family the metric belongs to: the Sōzu name, the unit, the
+    /// scope, then the `_total` suffix of counters. Times and percentiles are
+    /// converted to seconds, which their name announces. In OpenMetrics, the
+    /// `_total` suffix is carried by the samples, not by the family, so that
+    /// the samples are named the same in both formats.
+    fn family_name(&self, format: Format) -> String {
+        let unit = if self.in_seconds() { "_seconds" } else { "" };
+        let counter = self.metric_type == MetricType::Counter;
+        let family_name = format!(
+            "{}{unit}{}",
+            self.printable_name(),
+            self.scope.suffix(counter)
+        );
+
+        match format {
+            Format::Text if counter => format!("{family_name}_total"),
+            _ => family_name,
+        }
     }
 
     /// Create a type line, typically:
     ///
     /// # TYPE protocol_https gauge
-    fn type_line(&self) -> String {
-        let printable_metric_name = self.printable_name();
-        format!("# TYPE {} {}", printable_metric_name, self.metric_type)
+    fn type_line(&self, format: Format) -> String {
+        format!("# TYPE {} {}", self.family_name(format), self.metric_type)
+    }
+
+    /// Retrieve the description of the Sōzu metric, if any
+    fn description(&self, overrides: &BTreeMap<String, Description>) -> Option<Description> {
+        describe(&self.sozu_name.replace('.', "_"), overrides)
+    }
+
+    /// Whether the metric is a time measured by Sōzu in milliseconds and
+    /// exported in seconds
+    fn in_seconds(&self) -> bool {
+        matches!(
+            self.value.inner,
+            Some(Inner::Time(_) | Inner::Percentiles(_))
+        )
+    }
+
+    /// Retrieve the unit in which the metric is exported
+    fn unit<'a>(&self, description: Option<&'a Description>) -> Option<&'a str> {
+        if self.in_seconds() {
+            return Some("seconds");
+        }
+
+        description.and_then(|description| description.unit.as_deref())
+    }
+
+    /// Create a help line, typically:
+    ///
+    /// # HELP http_requests Number of HTTP requests
+    fn help_line(&self, format: Format, description: Option<&Description>) -> Option<String> {
+        let help = &description?.help;
+        // the catalogue describes times in milliseconds, as measured by Sōzu
+        let help = match help.strip_suffix(", in milliseconds") {
+            Some(help) if self.in_seconds() => format!("{help}, in seconds"),
+            _ => help.to_owned(),
+        };
+
+        let help = match format {
+            Format::Text => help.replace('\\', r"\\").replace('\n', r"\n"),
+            Format::OpenMetrics => openmetrics::escape(&help),
+        };
+
+        Some(format!("# HELP {} {help}", self.family_name(format)))
+    }
+
+    /// Create a unit line for OpenMetrics, typically:
+    ///
+    /// # UNIT backend_connect_time_seconds seconds
+    ///
+    /// The unit comes from the description or from the suffix of the name, and
+    /// is only announced if the name ends with it, as required by OpenMetrics.
+    fn unit_line(&self, format: Format, description: Option<&Description>) -> Option<String> {
+        if format != Format::OpenMetrics {
+            return None;
+        }
+
+        let family_name = self.family_name(format);
+        self.unit(description)
+            .into_iter()
+            .chain(UNITS)
+            .find(|unit| family_name.ends_with(&format!("_{unit}")))
+            .map(|unit| format!("# UNIT {family_name} {unit}"))
     }
 
     /// Format labels in a comma-separated list:
@@ -71,58 +387,145 @@ impl LabeledMetric {
             .join(",")
     }
 
+    /// Create one sample, the additional label is appended to the labels of
+    /// the metric. OpenMetrics samples without labels have no braces.
+    fn sample(
+        &self,
+        name: &str,
+        additional_label: Option<(&str, &str)>,
+        value: impl Display,
+        format: Format,
+    ) -> String {
+        let mut formatted_labels = self.formatted_labels();
+        if let Some((label_name, label_value)) = additional_label {
+            if !formatted_labels.is_empty() {
+                formatted_labels.push(',');
+            }
+            formatted_labels.push_str(&format!("{label_name}=\"{label_value}\""));
+        }
+
+        if formatted_labels.is_empty() && format == Format::OpenMetrics {
+            format!("{name} {value}")
+        } else {
+            format!("{name}{{{formatted_labels}}} {value}")
+        }
+    }
+
     /// Create a metric line, typically:
     ///
     /// ```plain
     /// http_active_requests{worker_id="0"} 0
     /// ```
-    /// For histograms, several lines are produced: sum, count, buckets
-    fn metric_line(&self) -> String {
-        let printable_metric_name = self.printable_name();
-        let formatted_labels = self.formatted_labels();
+    /// For histograms, several lines are produced: buckets, sum, count. In
+    /// OpenMetrics, the mandatory `+Inf` bucket is added.
+    ///
+    /// Percentiles are produced as a summary in seconds, time series as one
+    /// line per window, labelled with `window`.
+    fn metric_line(&self, format: Format) -> String {
+        let family_name = self.family_name(format);
         match &self.value.inner {
-            Some(inner) => {
-                match inner {
-                    Inner::Gauge(value) => {
-                        format!("{printable_metric_name}{{{formatted_labels}}} {value}")
-                    }
-                    Inner::Count(value) => {
-                        format!("{printable_metric_name}{{{formatted_labels}}} {value}")
-                    }
-                    Inner::Histogram(hist) => hist
+            Some(inner) => match inner {
+                Inner::Gauge(value) => self.sample(&family_name, None, value, format),
+                Inner::Count(value) if format == Format::OpenMetrics => {
+                    self.sample(&format!("{family_name}_total"), None, value, format)
+                }
+                Inner::Count(value) => self.sample(&family_name, None, value, format),
+                Inner::Histogram(hist) => {
+                    let bucket_name = format!("{family_name}_bucket");
+                    let mut lines = hist
                         .buckets
                         .iter()
                         .map(|bucket| {
-                            if formatted_labels.is_empty() {
-                                format!(
-                                    "{}_bucket{{le=\"{}\"}} {}\n",
-                                    printable_metric_name, bucket.le, bucket.count
-                                )
-                            } else {
-                                format!(
-                                    "{}_bucket{{{},le=\"{}\"}} {}\n",
-                                    printable_metric_name,
-                                    formatted_labels,
-                                    bucket.le,
-                                    bucket.count
-                                )
-                            }
+                            self.sample(
+                                &bucket_name,
+                                Some(("le", &bucket.le.to_string())),
+                                bucket.count,
+                                format,
+                            )
                         })
-                        .chain(iter::once(format!(
-                            "{}_sum{{{}}} {}\n",
-                            printable_metric_name, formatted_labels, hist.sum
-                        )))
-                        .chain(iter::once(format!(
-                            "{}_count{{{}}} {}",
-                            printable_metric_name, formatted_labels, hist.count
-                        )))
-                        .collect::<String>(),
-                    Inner::Time(_) | Inner::Percentiles(_) | Inner::TimeSerie(_) => {
-                        // should not happen at that point
-                        String::new()
+                        .collect::<Vec<_>>();
+
+                    if format == Format::OpenMetrics {
+                        lines.push(self.sample(
+                            &bucket_name,
+                            Some(("le", "+Inf")),
+                            hist.count,
+                            format,
+                        ));
                     }
+
+                    lines.push(self.sample(&format!("{family_name}_sum"), None, hist.sum, format));
+                    lines.push(self.sample(
+                        &format!("{family_name}_count"),
+                        None,
+                        hist.count,
+                        format,
+                    ));
+                    lines.join("\n")
                 }
-            }
+                Inner::Time(millis) => self.sample(&family_name, None, seconds(*millis), format),
+                Inner::Percentiles(percentiles) => {
+                    let quantiles = [
+                        ("0.5", percentiles.p_50),
+                        ("0.9", percentiles.p_90),
+                        ("0.99", percentiles.p_99),
+                        ("0.999", percentiles.p_99_9),
+                        ("0.9999", percentiles.p_99_99),
+                        ("1", percentiles.p_100),
+                    ];
+
+                    let mut lines = quantiles
+                        .iter()
+                        .map(|(quantile, value)| {
+                            self.sample(
+                                &family_name,
+                                Some(("quantile", quantile)),
+                                seconds(*value),
+                                format,
+                            )
+                        })
+                        .collect::<Vec<_>>();
+
+                    lines.push(self.sample(
+                        &format!("{family_name}_sum"),
+                        None,
+                        seconds(percentiles.sum),
+                        format,
+                    ));
+                    lines.push(self.sample(
+                        &format!("{family_name}_count"),
+                        None,
+                        percentiles.samples,
+                        format,
+                    ));
+                    lines.join("\n")
+                }
+                Inner::TimeSerie(time_serie) => {
+                    let windows = [
+                        (
+                            "last_minute",
+                            time_serie
+                                .last_minute
+                                .iter()
+                                .copied()
+                                .map(u64::from)
+                                .sum::<u64>(),
+                        ),
+                        (
+                            "last_hour",
+                            time_serie.last_hour.iter().copied().map(u64::from).sum(),
+                        ),
+                    ];
+
+                    windows
+                        .iter()
+                        .map(|(window, value)| {
+                            self.sample(&family_name, Some(("window", window)), value, format)
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+            },
             None => String::new(),
         }
     }
@@ -132,61 +535,120 @@ impl From<FilteredMetrics> for LabeledMetric {
     fn from(value: FilteredMetrics) -> Self {
         let metric_type = match &value.inner {
             Some(inner) => match inner {
-                Inner::Gauge(_) => MetricType::Gauge,
+                Inner::Gauge(_) | Inner::Time(_) | Inner::TimeSerie(_) => MetricType::Gauge,
                 Inner::Count(_) => MetricType::Counter,
                 Inner::Histogram(_) => MetricType::Histogram,
-                Inner::Time(_) | Inner::Percentiles(_) | Inner::TimeSerie(_) => {
-                    MetricType::Unsupported
-                }
+                Inner::Percentiles(_) => MetricType::Summary,
             },
             None => MetricType::Unsupported,
         };
         Self {
-            metric_name: String::new(),
+            sozu_name: String::new(),
+            scope: Scope::default(),
             labels: Vec::new(),
             value,
             metric_type,
+            source: 0,
         }
     }
 }
 
+// -----------------------------------------------------------------------------
+// helpers
+
+/// Convert a time measured by Sōzu in milliseconds to seconds
+fn seconds(millis: u64) -> f64 {
+    millis as f64 / 1000.0
+}
+
 /// Convert aggregated metrics into prometheus serialize one
 ///
 /// When `per_worker_metrics` is `true`, per-worker series (labelled with
 /// `worker_id`) are emitted in addition to the aggregated ones.
-#[tracing::instrument(skip_all)]
+///
+/// Families are described with the built-in catalogue of Sōzu metrics, unless
+/// `descriptions` overrides them. Label values are written according to
+/// `label_encoding`.
+///
+/// In [`Format::OpenMetrics`], the `# EOF` terminator is not written, as the
+/// families of the connector have to be merged with the output.
 pub fn convert_metrics_to_prometheus(
     aggregated_metrics: AggregatedMetrics,
     per_worker_metrics: bool,
+    format: Format,
+    descriptions: &BTreeMap<String, Description>,
+    label_encoding: LabelEncoding,
 ) -> String {
-    debug!("Converting metrics to prometheus format");
-    let labeled_metrics = apply_labels(aggregated_metrics, per_worker_metrics);
+    let (prometheus_metrics, _) = convert_instances_to_prometheus(
+        vec![(None, aggregated_metrics)],
+        per_worker_metrics,
+        format,
+        descriptions,
+        label_encoding,
+    );
+
+    prometheus_metrics
+}
+
+/// Convert the aggregated metrics of several Sōzu instances, see
+/// [`convert_metrics_to_prometheus`]. The metrics of an instance given with a
+/// name are labelled with `instance`, families are shared by all instances.
+///
+/// Returns the number of series produced for each instance, in order, along
+/// with the serialized metrics.
+#[tracing::instrument(skip_all)]
+pub fn convert_instances_to_prometheus(
+    instances: Vec<(Option<String>, AggregatedMetrics)>,
+    per_worker_metrics: bool,
+    format: Format,
+    descriptions: &BTreeMap<String, Description>,
+    label_encoding: LabelEncoding,
+) -> (String, Vec<usize>) {
+    debug!(format = ?format, "Converting metrics to prometheus format");
+    let mut series = vec![0; instances.len()];
+    let mut labeled_metrics = Vec::new();
+    for (source, (instance, aggregated_metrics)) in instances.into_iter().enumerate() {
+        let mut metrics = apply_labels(aggregated_metrics, per_worker_metrics, label_encoding);
+        for metric in &mut metrics {
+            metric.source = source;
+            if let Some(instance) = &instance {
+                metric.with_label("instance", instance, label_encoding);
+            }
+        }
 
-    let metric_names = get_unique_metric_names(&labeled_metrics);
+        labeled_metrics.append(&mut metrics);
+    }
+
+    let family_names = get_unique_family_names(&labeled_metrics, format);
 
     let mut prometheus_metrics = String::new();
 
-    for metric_name in metric_names {
-        prometheus_metrics.push_str(&produce_lines_for_one_metric_name(
+    for family_name in family_names {
+        prometheus_metrics.push_str(&produce_lines_for_one_family(
             &labeled_metrics,
-            &metric_name,
+            &family_name,
+            format,
+            descriptions,
+            &mut series,
         ));
     }
 
-    prometheus_metrics
+    (prometheus_metrics, series)
 }
 
 /// assign worker_id and cluster_id as labels
 fn apply_labels(
     aggregated_metrics: AggregatedMetrics,
     per_worker_metrics: bool,
+    label_encoding: LabelEncoding,
 ) -> Vec<LabeledMetric> {
     let mut labeled_metrics = Vec::new();
 
     // metrics of the main process
     for (metric_name, value) in aggregated_metrics.main.iter() {
         let mut labeled = LabeledMetric::from(value.clone());
-        labeled.with_name(&format!("{metric_name}_main"));
+        labeled.with_name(metric_name);
+        labeled.with_scope(Scope::Main);
 
         labeled_metrics.push(labeled);
     }
@@ -194,7 +656,8 @@ fn apply_labels(
     // proxying metrics
     for (metric_name, value) in aggregated_metrics.proxying.iter() {
         let mut labeled = LabeledMetric::from(value.clone());
-        labeled.with_name(&format!("{metric_name}_total"));
+        labeled.with_name(metric_name);
+        labeled.with_scope(Scope::Proxying);
 
         labeled_metrics.push(labeled);
     }
@@ -204,7 +667,7 @@ fn apply_labels(
         for (metric_name, value) in cluster_metrics.cluster {
             let mut labeled = LabeledMetric::from(value.clone());
             labeled.with_name(&metric_name);
-            labeled.with_label("cluster_id", &cluster_id);
+            labeled.with_label("cluster_id", &cluster_id, label_encoding);
             labeled_metrics.push(labeled);
         }
 
@@ -218,8 +681,8 @@ fn apply_labels(
             for (metric_name, value) in metrics {
                 let mut labeled = LabeledMetric::from(value.clone());
                 labeled.with_name(&metric_name);
-                labeled.with_label("cluster_id", &cluster_id);
-                labeled.with_label("backend_id", &backend_id);
+                labeled.with_label("cluster_id", &cluster_id, label_encoding);
+                labeled.with_label("backend_id", &backend_id, label_encoding);
                 labeled_metrics.push(labeled);
             }
         }
@@ -236,8 +699,9 @@ fn apply_labels(
             // never share a metric name with the aggregated `_total` series.
             for (metric_name, value) in proxy {
                 let mut labeled = LabeledMetric::from(value);
-                labeled.with_name(&format!("{metric_name}_worker"));
-                labeled.with_label("worker_id", &worker_id);
+                labeled.with_name(&metric_name);
+                labeled.with_scope(Scope::Worker);
+                labeled.with_label("worker_id", &worker_id, label_encoding);
                 labeled_metrics.push(labeled);
             }
 
@@ -249,8 +713,8 @@ fn apply_labels(
                 for (metric_name, value) in cluster_metrics.cluster {
                     let mut labeled = LabeledMetric::from(value);
                     labeled.with_name(&metric_name);
-                    labeled.with_label("worker_id", &worker_id);
-                    labeled.with_label("cluster_id", &cluster_id);
+                    labeled.with_label("worker_id", &worker_id, label_encoding);
+                    labeled.with_label("cluster_id", &cluster_id, label_encoding);
                     labeled_metrics.push(labeled);
                 }
 
@@ -263,9 +727,9 @@ fn apply_labels(
                     for (metric_name, value) in metrics {
                         let mut labeled = LabeledMetric::from(value);
                         labeled.with_name(&metric_name);
-                        labeled.with_label("worker_id", &worker_id);
-                        labeled.with_label("cluster_id", &cluster_id);
-                        labeled.with_label("backend_id", &backend_id);
+                        labeled.with_label("worker_id", &worker_id, label_encoding);
+                        labeled.with_label("cluster_id", &cluster_id, label_encoding);
+                        labeled.with_label("backend_id", &backend_id, label_encoding);
                         labeled_metrics.push(labeled);
                     }
                 }
@@ -276,26 +740,30 @@ fn apply_labels(
     labeled_metrics
 }
 
-fn get_unique_metric_names(labeled_metrics: &Vec<LabeledMetric>) -> Vec<String> {
+fn get_unique_family_names(labeled_metrics: &Vec<LabeledMetric>, format: Format) -> Vec<String> {
     let mut names = Vec::new();
     for metric in labeled_metrics {
-        if !names.contains(&metric.metric_name) {
-            names.push(metric.metric_name.clone());
+        let family_name = metric.family_name(format);
+        if !names.contains(&family_name) {
+            names.push(family_name);
         }
     }
     names
 }
 
-fn produce_lines_for_one_metric_name(
+fn produce_lines_for_one_family(
     labeled_metrics: &Vec<LabeledMetric>,
-    metric_name: &str,
+    family_name: &str,
+    format: Format,
+    descriptions: &BTreeMap<String, Description>,
+    series: &mut [usize],
 ) -> String {
     let mut lines = String::new();
 
     // find the first item to produce the type line only once
     let first_item = match labeled_metrics
         .iter()
-        .find(|metric| metric.metric_name == metric_name)
+        .find(|metric| metric.family_name(format) == family_name)
     {
         Some(item) => item,
         None => return String::new(),
@@ -303,14 +771,46 @@ fn produce_lines_for_one_metric_name(
     if first_item.metric_type == MetricType::Unsupported {
         return String::new();
     }
-    lines.push_str(&first_item.type_line());
+
+    // the text format conventionally announces the help first, OpenMetrics
+    // the type first
+    let description = first_item.description(descriptions);
+    let help_line = first_item.help_line(format, description.as_ref());
+    if let (Format::Text, Some(help_line)) = (format, &help_line) {
+        lines.push_str(help_line);
+        lines.push('\n');
+    }
+
+    lines.push_str(&first_item.type_line(format));
     lines.push('\n');
+    if let Some(unit_line) = first_item.unit_line(format, description.as_ref()) {
+        lines.push_str(&unit_line);
+        lines.push('\n');
+    }
+
+    if let (Format::OpenMetrics, Some(help_line)) = (format, &help_line) {
+        lines.push_str(help_line);
+        lines.push('\n');
+    }
 
     for metric in labeled_metrics {
-        if metric.metric_name == metric_name {
-            lines.push_str(&metric.metric_line());
-            lines.push('\n');
+        if metric.family_name(format) != family_name {
+            continue;
+        }
+
+        // OpenMetrics forbids a family to hold samples of several types
+        if format == Format::OpenMetrics && metric.metric_type != first_item.metric_type {
+            debug!(
+                family = family_name,
+                "Skipping metric whose type differs from its family"
+            );
+            continue;
         }
+
+        let metric_line = metric.metric_line(format);
+        series[metric.source] += metric_line.lines().count();
+        lines.push_str(&metric_line);
+        lines.push('\n');
     }
 
     lines
@@ -318,11 +818,11 @@ fn produce_lines_for_one_metric_name(
 
 #[cfg(test)]
 mod test {
-    use std::collections::BTreeMap;
+    use std::collections::{BTreeMap, BTreeSet};
 
     use sozu_command_lib::proto::command::{
-        filtered_metrics::Inner, AggregatedMetrics, BackendMetrics, ClusterMetrics,
-        FilteredMetrics, WorkerMetrics,
+        filtered_metrics::Inner, AggregatedMetrics, BackendMetrics, Bucket, ClusterMetrics,
+        FilteredHistogram, FilteredMetrics, FilteredTimeSerie, Percentiles, WorkerMetrics,
     };
 
     use super::*;
@@ -351,7 +851,13 @@ mod test {
             ..Default::default()
         };
 
-        let prometheus_metrics = convert_metrics_to_prometheus(aggregated_metrics, false);
+        let prometheus_metrics = convert_metrics_to_prometheus(
+            aggregated_metrics,
+            false,
+            Format::Text,
+            &BTreeMap::new(),
+            LabelEncoding::Url,
+        );
 
         let expected = r#"# TYPE http_response_status gauge
 http_response_status{cluster_id="http%3A%2F%2Fmy-cluster-id.com%2Fapi%3Fparam%3Dvalue"} 3
@@ -410,24 +916,37 @@ http_response_status{cluster_id="http%3A%2F%2Fmy-cluster-id.com%2Fapi%3Fparam%3D
 
         // disabled -> no per-worker series at all
         assert_eq!(
-            convert_metrics_to_prometheus(aggregated_metrics.clone(), false),
+            convert_metrics_to_prometheus(
+                aggregated_metrics.clone(),
+                false,
+                Format::Text,
+                &BTreeMap::new(),
+                LabelEncoding::Url,
+            ),
             String::new()
         );
 
         // enabled -> per-worker series carry the expected labels
-        let prometheus_metrics = convert_metrics_to_prometheus(aggregated_metrics, true);
+        let prometheus_metrics = convert_metrics_to_prometheus(
+            aggregated_metrics,
+            true,
+            Format::Text,
+            &BTreeMap::new(),
+            LabelEncoding::Url,
+        );
 
         assert!(
-            prometheus_metrics.contains(r#"bytes_in_worker{worker_id="0"} 246"#),
+            prometheus_metrics.contains(r#"bytes_in_worker_total{worker_id="0"} 246"#),
             "missing worker proxy series, got:\n{prometheus_metrics}"
         );
         assert!(
-            prometheus_metrics.contains(r#"requests{worker_id="0",cluster_id="MyCluster"} 10"#),
+            prometheus_metrics
+                .contains(r#"requests_cluster_total{worker_id="0",cluster_id="MyCluster"} 10"#),
             "missing per-worker cluster series, got:\n{prometheus_metrics}"
         );
         assert!(
             prometheus_metrics.contains(
-                r#"requests{worker_id="0",cluster_id="MyCluster",backend_id="backend-1"} 4"#
+                r#"requests_cluster_total{worker_id="0",cluster_id="MyCluster",backend_id="backend-1"} 4"#
             ),
             "missing per-worker backend series, got:\n{prometheus_metrics}"
         );
@@ -442,15 +961,407 @@ http_response_status{cluster_id="http%3A%2F%2Fmy-cluster-id.com%2Fapi%3Fparam%3D
 
         assert_eq!(labeled_metric.formatted_labels(), "");
 
-        labeled_metric.with_label("le", "3");
+        labeled_metric.with_label("le", "3", LabelEncoding::Url);
 
         assert_eq!(labeled_metric.formatted_labels(), r#"le="3""#);
 
-        labeled_metric.with_label("cluster_id", "http://my-cluster-id.com/api?param=value");
+        labeled_metric.with_label(
+            "cluster_id",
+            "http://my-cluster-id.com/api?param=value",
+            LabelEncoding::Url,
+        );
 
         assert_eq!(
             labeled_metric.formatted_labels(),
             r#"le="3",cluster_id="http%3A%2F%2Fmy-cluster-id.com%2Fapi%3Fparam%3Dvalue""#
         )
     }
+
+    #[test]
+    fn escape_labels() {
+        let metric = FilteredMetrics {
+            inner: Some(Inner::Count(3)),
+        };
+        let mut labeled_metric = LabeledMetric::from(metric);
+
+        labeled_metric.with_label(
+            "cluster_id",
+            "http://my-cluster-id.com/api?param=value",
+            LabelEncoding::Escape,
+        );
+        labeled_metric.with_label("backend_id", "a \"quoted\\ name\n", LabelEncoding::Escape);
+
+        assert_eq!(
+            labeled_metric.formatted_labels(),
+            r#"cluster_id="http://my-cluster-id.com/api?param=value",backend_id="a \"quoted\\ name\n""#
+        )
+    }
+
+    #[test]
+    fn negotiate_format() {
+        assert_eq!(Format::negotiate(None), Format::Text);
+        assert_eq!(Format::negotiate(Some("text/plain")), Format::Text);
+        assert_eq!(
+            Format::negotiate(Some(
+                "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"
+            )),
+            Format::OpenMetrics
+        );
+        assert_eq!(
+            Format::negotiate(Some("application/openmetrics-text; q=0, text/plain")),
+            Format::Text
+        );
+    }
+
+    #[test]
+    fn encode_openmetrics() {
+        let mut proxying = BTreeMap::new();
+        proxying.insert(
+            "bytes_in".to_owned(),
+            FilteredMetrics {
+                inner: Some(Inner::Count(42)),
+            },
+        );
+
+        let mut cluster = BTreeMap::new();
+        cluster.insert(
+            "bytes_in".to_owned(),
+            FilteredMetrics {
+                inner: Some(Inner::Count(10)),
+            },
+        );
+        cluster.insert(
+            "request_time_seconds".to_owned(),
+            FilteredMetrics {
+                inner: Some(Inner::Histogram(FilteredHistogram {
+                    sum: 7,
+                    count: 3,
+                    buckets: vec![Bucket { count: 1, le: 1 }, Bucket { count: 2, le: 5 }],
+                })),
+            },
+        );
+
+        let mut clusters = BTreeMap::new();
+        clusters.insert(
+            "MyCluster".to_owned(),
+            ClusterMetrics {
+                cluster,
+                backends: Vec::new(),
+            },
+        );
+
+        let aggregated_metrics = AggregatedMetrics {
+            proxying,
+            clusters,
+            ..Default::default()
+        };
+
+        // the proxying and the per-cluster families stay distinct, so that
+        // aggregating the per-cluster samples does not count the total twice
+        let expected = r#"# TYPE bytes_in counter
+# HELP bytes_in Bytes received from clients, in bytes
+bytes_in_total 42
+# TYPE bytes_in_cluster counter
+# HELP bytes_in_cluster Bytes received from clients, in bytes
+bytes_in_cluster_total{cluster_id="MyCluster"} 10
+# TYPE request_time_seconds histogram
+# UNIT request_time_seconds seconds
+request_time_seconds_bucket{cluster_id="MyCluster",le="1"} 1
+request_time_seconds_bucket{cluster_id="MyCluster",le="5"} 2
+request_time_seconds_bucket{cluster_id="MyCluster",le="+Inf"} 3
+request_time_seconds_sum{cluster_id="MyCluster"} 7
+request_time_seconds_count{cluster_id="MyCluster"} 3
+"#;
+
+        assert_eq!(
+            convert_metrics_to_prometheus(
+                aggregated_metrics,
+                false,
+                Format::OpenMetrics,
+                &BTreeMap::new(),
+                LabelEncoding::Url,
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn name_samples_alike_in_both_formats() {
+        let metrics = || {
+            let count = FilteredMetrics {
+                inner: Some(Inner::Count(1)),
+            };
+            let gauge = FilteredMetrics {
+                inner: Some(Inner::Gauge(1)),
+            };
+            let time = FilteredMetrics {
+                inner: Some(Inner::Time(1)),
+            };
+            let histogram = FilteredMetrics {
+                inner: Some(Inner::Histogram(FilteredHistogram {
+                    sum: 1,
+                    count: 1,
+                    buckets: vec![Bucket { count: 1, le: 1 }],
+                })),
+            };
+
+            BTreeMap::from([
+                ("bytes_in".to_owned(), count),
+                ("connections".to_owned(), gauge),
+                ("request_time".to_owned(), time),
+                ("response_time".to_owned(), histogram),
+            ])
+        };
+
+        let aggregated_metrics = AggregatedMetrics {
+            main: metrics(),
+            proxying: metrics(),
+            clusters: BTreeMap::from([(
+                "MyCluster".to_owned(),
+                ClusterMetrics {
+                    cluster: metrics(),
+                    backends: vec![BackendMetrics {
+                        backend_id: "MyBackend".to_owned(),
+                        metrics: metrics(),
+                    }],
+                },
+            )]),
+            workers: BTreeMap::from([(
+                "0".to_owned(),
+                WorkerMetrics {
+                    proxy: metrics(),
+                    clusters: BTreeMap::new(),
+                },
+            )]),
+        };
+
+        // the family of each sample, and the name of each sample
+        let samples = |format| {
+            let output = convert_metrics_to_prometheus(
+                aggregated_metrics.clone(),
+                true,
+                format,
+                &BTreeMap::new(),
+                LabelEncoding::Url,
+            );
+
+            let mut family = String::new();
+            let mut samples = BTreeSet::new();
+            for line in output.lines() {
+                if let Some(name) = line.strip_prefix("# TYPE ") {
+                    family = name.split(' ').next().unwrap_or_default().to_owned();
+                } else if !line.starts_with('#') {
+                    let name = line.split(['{', ' ']).next().unwrap_or_default();
+                    samples.insert((family.to_owned(), name.to_owned()));
+                }
+            }
+            samples
+        };
+
+        let text = samples(Format::Text);
+        let openmetrics = samples(Format::OpenMetrics);
+        assert_eq!(
+            text.iter().map(|(_, name)| name).collect::<BTreeSet<_>>(),
+            openmetrics
+                .iter()
+                .map(|(_, name)| name)
+                .collect::<BTreeSet<_>>(),
+        );
+        assert!(openmetrics.contains(&("bytes_in".to_owned(), "bytes_in_total".to_owned())));
+
+        // no sample is named after another family
+        for (family, name) in &openmetrics {
+            assert!(
+                !openmetrics
+                    .iter()
+                    .any(|(other, _)| other != family && other == name),
+                "sample {name} of family {family} is named after another family"
+            );
+        }
+    }
+
+    #[test]
+    fn encode_help() {
+        let mut proxying = BTreeMap::new();
+        proxying.insert(
+            "http.requests".to_owned(),
+            FilteredMetrics {
+                inner: Some(Inner::Count(12)),
+            },
+        );
+        proxying.insert(
+            "my.metric".to_owned(),
+            FilteredMetrics {
+                inner: Some(Inner::Gauge(1)),
+            },
+        );
+
+        let aggregated_metrics = AggregatedMetrics {
+            proxying,
+            ..Default::default()
+        };
+
+        let mut descriptions = BTreeMap::new();
+        descriptions.insert(
+            "my_metric".to_owned(),
+            Description::new("My \"own\" metric", None),
+        );
+
+        let expected = r#"# HELP http_requests_total Number of HTTP requests
+# TYPE http_requests_total counter
+http_requests_total{} 12
+# HELP my_metric_total My "own" metric
+# TYPE my_metric_total gauge
+my_metric_total{} 1
+"#;
+
+        assert_eq!(
+            convert_metrics_to_prometheus(
+                aggregated_metrics,
+                false,
+                Format::Text,
+                &descriptions,
+                LabelEncoding::Url
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn encode_time_percentiles_and_time_serie() {
+        let mut proxying = BTreeMap::new();
+        proxying.insert(
+            "request_time".to_owned(),
+            FilteredMetrics {
+                inner: Some(Inner::Time(1500)),
+            },
+        );
+        proxying.insert(
+            "backend_response_time".to_owned(),
+            FilteredMetrics {
+                inner: Some(Inner::Percentiles(Percentiles {
+                    samples: 10,
+                    p_50: 1,
+                    p_90: 2,
+                    p_99: 3,
+                    p_99_9: 4,
+                    p_99_99: 5,
+                    p_99_999: 6,
+                    p_100: 7,
+                    sum: 25,
+                })),
+            },
+        );
+        proxying.insert(
+            "http.requests".to_owned(),
+            FilteredMetrics {
+                inner: Some(Inner::TimeSerie(FilteredTimeSerie {
+                    last_second: 1,
+                    last_minute: vec![1, 2, 3],
+                    last_hour: vec![6, 10],
+                })),
+            },
+        );
+
+        let aggregated_metrics = AggregatedMetrics {
+            proxying,
+            ..Default::default()
+        };
+
+        // the unit comes before the `_total` suffix of the proxying scope
+        let expected = r#"# TYPE backend_response_time_seconds_total summary
+# HELP backend_response_time_seconds_total Time for a backend to answer a request, in seconds
+backend_response_time_seconds_total{quantile="0.5"} 0.001
+backend_response_time_seconds_total{quantile="0.9"} 0.002
+backend_response_time_seconds_total{quantile="0.99"} 0.003
+backend_response_time_seconds_total{quantile="0.999"} 0.004
+backend_response_time_seconds_total{quantile="0.9999"} 0.005
+backend_response_time_seconds_total{quantile="1"} 0.007
+backend_response_time_seconds_total_sum 0.025
+backend_response_time_seconds_total_count 10
+# TYPE http_requests_total gauge
+# HELP http_requests_total Number of HTTP requests
+http_requests_total{window="last_minute"} 6
+http_requests_total{window="last_hour"} 16
+# TYPE request_time_seconds_total gauge
+# HELP request_time_seconds_total Time to serve a request, in seconds
+request_time_seconds_total 1.5
+"#;
+
+        assert_eq!(
+            convert_metrics_to_prometheus(
+                aggregated_metrics,
+                false,
+                Format::OpenMetrics,
+                &BTreeMap::new(),
+                LabelEncoding::Url,
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn encode_instances() {
+        let aggregated_metrics = |value| {
+            let mut proxying = BTreeMap::new();
+            proxying.insert(
+                "http.requests".to_owned(),
+                FilteredMetrics {
+                    inner: Some(Inner::Count(value)),
+                },
+            );
+
+            AggregatedMetrics {
+                proxying,
+                ..Default::default()
+            }
+        };
+
+        let expected = r#"# HELP http_requests_total Number of HTTP requests
+# TYPE http_requests_total counter
+http_requests_total{instance="public"} 12
+http_requests_total{instance="internal"} 3
+"#;
+
+        assert_eq!(
+            convert_instances_to_prometheus(
+                vec![
+                    (Some("public".to_owned()), aggregated_metrics(12)),
+                    (Some("internal".to_owned()), aggregated_metrics(3)),
+                ],
+                false,
+                Format::Text,
+                &BTreeMap::new(),
+                LabelEncoding::Url,
+            ),
+            (expected.to_owned(), vec![1, 1])
+        );
+    }
+
+    #[test]
+    fn encode_gauge_family() {
+        let mut up = GaugeFamily::new("sozu_up", "Whether Sōzu could be scraped");
+        assert_eq!(up.encode(Format::Text, LabelEncoding::Url), "");
+
+        up.with_sample(&[("instance", "public edge")], 0.0);
+        up.with_sample(&[], 1.0);
+
+        assert_eq!(
+            up.encode(Format::Text, LabelEncoding::Url),
+            r#"# HELP sozu_up Whether Sōzu could be scraped
+# TYPE sozu_up gauge
+sozu_up{instance="public%20edge"} 0
+sozu_up{} 1
+"#
+        );
+
+        assert_eq!(
+            up.encode(Format::OpenMetrics, LabelEncoding::Escape),
+            r#"# TYPE sozu_up gauge
+# HELP sozu_up Whether Sōzu could be scraped
+sozu_up{instance="public edge"} 0
+sozu_up 1
+"#
+        );
+    }
 }